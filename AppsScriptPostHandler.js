@@ -162,7 +162,10 @@ function doPost(e) {
 
     // Parse the incoming JSON payload
     try{
-      const data = getDataOut(JSON.parse(e.postData.contents));
+      // Either the rows on their own, or { referrals: [...], ...extra sections } when extra sections are turned on
+      const payload = getDataOut(JSON.parse(e.postData.contents));
+      const data = Array.isArray(payload) ? payload : payload.referrals;
+      if (!Array.isArray(payload)) writeExtraSections(payload);
     
       // Convert the data to a 2D array
       const pivotedData = convertTo2DArray(data);
@@ -181,6 +184,48 @@ function doPost(e) {
     }
 }
 
+/**
+ * writes each extra section of the payload to the tab with the same name, e.g. "diff".
+ * sections without a tab are skipped, so add a tab for the ones you want.
+ *
+ * @param {Object} payload - the decrypted payload
+ */
+function writeExtraSections(payload) {
+  Object.keys(payload).filter((key) => key !== "referrals").forEach((key) => {
+    const target = sheet.getSheetByName(key);
+    if (!target) return;
+    const rows = sectionToRows(payload[key]);
+    target.clear();
+    if (rows.length > 0) target.getRange(1, 1, rows.length, rows[0].length).setValues(rows);
+  });
+}
+
+/**
+ * turns a section into rectangular rows. a list becomes a table; an object of
+ * lists becomes one table per list, labelled in the first column.
+ */
+function sectionToRows(section) {
+  if (Array.isArray(section)) return convertTo2DArray(section);
+  const rows = [];
+  Object.keys(section).forEach((name) => {
+    const list = Array.isArray(section[name]) ? section[name] : [section[name]];
+    const table = convertTo2DArray(list.map((item) => flatten(item)));
+    if (table.length === 0) rows.push([name, "none"]);
+    table.forEach((row, i) => rows.push([i === 0 ? name : ""].concat(row)));
+  });
+  const width = Math.max(...rows.map((row) => row.length));
+  return rows.map((row) => row.concat(Array(width - row.length).fill("")));
+
+  function flatten(item) {
+    if (item === null || typeof item !== "object") return { value: item };
+    const flat = {};
+    Object.keys(item).forEach((k) => {
+      flat[k] = item[k] !== null && typeof item[k] === "object" ? JSON.stringify(item[k]) : item[k];
+    });
+    return flat;
+  }
+}
+
 function convertTo2DArray(arr) {
   try{if (arr.length === 0) return []; // Return empty array if input is empty
 
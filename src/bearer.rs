@@ -1,6 +1,9 @@
 // Jackson Coxson & Adam Morgan
 
+use std::collections::HashMap;
+
 use base64::Engine;
+use chrono::{ DateTime, Duration, Utc };
 use log::error;
 use serde::{ Deserialize, Serialize };
 
@@ -14,6 +17,18 @@ pub struct BearerToken {
 pub struct Claims {
     #[serde(rename = "missionId")]
     pub mission_id: usize,
+
+    /// Expiry, seconds since the epoch
+    #[serde(default)]
+    pub exp: Option<i64>,
+
+    /// Issued at, seconds since the epoch
+    #[serde(default)]
+    pub iat: Option<i64>,
+
+    /// Every other claim, kept as-is
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
 }
 
 impl BearerToken {
@@ -52,4 +67,61 @@ impl BearerToken {
             }
         }
     }
+
+    /// When the token stops being accepted, if it says
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp(self.claims.exp?, 0)
+    }
+
+    /// True if the token expires within `skew` from now.
+    /// Tokens without an `exp` claim are assumed to still be good.
+    pub fn is_expired(&self, skew: Duration) -> bool {
+        match self.expires_at() {
+            Some(expires_at) => Utc::now() + skew >= expires_at,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token_with(claims: serde_json::Value) -> String {
+        let claims = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(claims.to_string());
+        format!("eyJhbGciOiJIUzI1NiJ9.{claims}.signature")
+    }
+
+    #[test]
+    fn parses_times_and_keeps_unknown_claims() {
+        let token = token_with(serde_json::json!({
+            "missionId": 42,
+            "exp": 2_000_000_000,
+            "iat": 1_999_996_400,
+            "sub": "someone"
+        }));
+        let token = BearerToken::from_base64(token).unwrap();
+        assert_eq!(token.claims.mission_id, 42);
+        assert_eq!(token.claims.iat, Some(1_999_996_400));
+        assert_eq!(token.expires_at().unwrap().timestamp(), 2_000_000_000);
+        assert_eq!(token.claims.extra["sub"], "someone");
+    }
+
+    #[test]
+    fn expiry_respects_skew() {
+        let in_a_minute = (Utc::now() + Duration::seconds(60)).timestamp();
+        let token = token_with(serde_json::json!({ "missionId": 1, "exp": in_a_minute }));
+        let token = BearerToken::from_base64(token).unwrap();
+        assert!(!token.is_expired(Duration::zero()));
+        assert!(token.is_expired(Duration::minutes(5)));
+
+        let expired = token_with(serde_json::json!({ "missionId": 1, "exp": 1 }));
+        assert!(BearerToken::from_base64(expired).unwrap().is_expired(Duration::zero()));
+    }
+
+    #[test]
+    fn missing_exp_never_expires() {
+        let token = BearerToken::from_base64(token_with(serde_json::json!({ "missionId": 1 }))).unwrap();
+        assert!(!token.is_expired(Duration::days(365)));
+    }
 }
@@ -0,0 +1,235 @@
+// People list snapshots kept in `working_path/people_lists`, named by the unix
+// second they were taken
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{cli::CacheAction, persons, settings::Settings};
+
+pub const PEOPLE_LISTS_DIR: &str = "people_lists";
+pub const TIMELINES_DIR: &str = "timelines";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheSettings {
+    /// How long the newest snapshot is used instead of asking referral manager
+    pub ttl_minutes: u64,
+    /// Snapshots beyond the newest this many are deleted. 0 keeps them all.
+    pub keep_last: usize,
+    /// Snapshots older than this are deleted. 0 keeps them all.
+    pub keep_days: u64,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            ttl_minutes: 60,
+            keep_last: 10,
+            keep_days: 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    /// Unix seconds
+    pub taken_at: u64,
+}
+
+impl Snapshot {
+    pub fn age_secs(&self, now: u64) -> u64 {
+        now.saturating_sub(self.taken_at)
+    }
+}
+
+pub struct SnapshotCache {
+    dir: PathBuf,
+    settings: CacheSettings,
+}
+
+impl SnapshotCache {
+    pub fn new(working_path: &Path, settings: CacheSettings) -> Self {
+        Self {
+            dir: working_path.join(PEOPLE_LISTS_DIR),
+            settings,
+        }
+    }
+
+    /// Every snapshot, newest first. Files that aren't named `{timestamp}.json` are left alone.
+    pub fn list(&self) -> std::io::Result<Vec<Snapshot>> {
+        if !self.dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut snapshots = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let path = entry.path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            if let Some(taken_at) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                snapshots.push(Snapshot { path, taken_at });
+            }
+        }
+        snapshots.sort_by_key(|s| std::cmp::Reverse(s.taken_at));
+        Ok(snapshots)
+    }
+
+    /// The newest snapshot, if it's younger than the TTL
+    pub fn fresh(&self, now: u64) -> std::io::Result<Option<Snapshot>> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .next()
+            .filter(|s| s.taken_at <= now && s.age_secs(now) < self.settings.ttl_minutes * 60))
+    }
+
+    pub fn load(&self, snapshot: &Snapshot) -> crate::error::Result<Vec<persons::Person>> {
+        Ok(persons::Person::parse_lossy(serde_json::from_str(
+            &std::fs::read_to_string(&snapshot.path)?,
+        )?))
+    }
+
+    pub fn save(&self, now: u64, list: &[persons::Person]) -> crate::error::Result<Snapshot> {
+        std::fs::create_dir_all(&self.dir)?;
+        let snapshot = Snapshot {
+            path: self.dir.join(format!("{now}.json")),
+            taken_at: now,
+        };
+        let file = std::fs::File::create(&snapshot.path)?;
+        serde_json::to_writer(std::io::BufWriter::new(file), &json!({ "persons": list }))?;
+        Ok(snapshot)
+    }
+
+    /// Deletes snapshots the retention policy doesn't keep and returns them
+    pub fn prune(&self, now: u64) -> std::io::Result<Vec<Snapshot>> {
+        let mut removed = Vec::new();
+        for (i, snapshot) in self.list()?.into_iter().enumerate() {
+            let too_many = self.settings.keep_last > 0 && i >= self.settings.keep_last;
+            let too_old = self.settings.keep_days > 0 && snapshot.age_secs(now) > self.settings.keep_days * 24 * 60 * 60;
+            if too_many || too_old {
+                std::fs::remove_file(&snapshot.path)?;
+                removed.push(snapshot);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Deletes every snapshot and returns how many there were
+    pub fn clear(&self) -> std::io::Result<usize> {
+        let snapshots = self.list()?;
+        for snapshot in &snapshots {
+            std::fs::remove_file(&snapshot.path)?;
+        }
+        Ok(snapshots.len())
+    }
+}
+
+pub fn cache_command(action: CacheAction) -> anyhow::Result<()> {
+    let working_path = crate::env::default_working_path();
+    let settings = Settings::load(&working_path)?;
+    let cache = SnapshotCache::new(&working_path, settings.cache);
+    let now = chrono::Utc::now().timestamp().max(0) as u64;
+    match action {
+        CacheAction::List => {
+            let snapshots = cache.list()?;
+            if snapshots.is_empty() {
+                println!("No people list snapshots saved");
+            }
+            for snapshot in snapshots {
+                let taken = chrono::DateTime::from_timestamp(snapshot.taken_at as i64, 0).unwrap_or_default();
+                let size = std::fs::metadata(&snapshot.path).map(|m| m.len()).unwrap_or_default();
+                println!(
+                    "{}  {}  {:>6} KiB  {} minutes old",
+                    snapshot.taken_at,
+                    taken.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"),
+                    size / 1024,
+                    snapshot.age_secs(now) / 60
+                );
+            }
+        }
+        CacheAction::Prune => {
+            let removed = cache.prune(now)?;
+            println!("Removed {} people list snapshots", removed.len());
+        }
+        CacheAction::Clear => {
+            println!("Removed {} people list snapshots", cache.clear()?);
+            let timelines = working_path.join(TIMELINES_DIR);
+            if timelines.is_dir() {
+                std::fs::remove_dir_all(&timelines)?;
+                println!("Removed the cached timelines");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60;
+    const DAY: u64 = 24 * HOUR;
+
+    fn cache_with(dir: &Path, settings: CacheSettings, taken: &[u64]) -> SnapshotCache {
+        let cache = SnapshotCache::new(dir, settings);
+        for t in taken {
+            cache.save(*t, &[]).unwrap();
+        }
+        cache
+    }
+
+    fn taken(snapshots: Vec<Snapshot>) -> Vec<u64> {
+        snapshots.into_iter().map(|s| s.taken_at).collect()
+    }
+
+    #[test]
+    fn lists_newest_first_and_ignores_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache_with(dir.path(), CacheSettings::default(), &[5_000, 90_000, 10_000]);
+        std::fs::write(dir.path().join(PEOPLE_LISTS_DIR).join("notes.txt"), "").unwrap();
+        std::fs::write(dir.path().join(PEOPLE_LISTS_DIR).join("latest.json"), "{}").unwrap();
+        assert_eq!(taken(cache.list().unwrap()), vec![90_000, 10_000, 5_000]);
+    }
+
+    #[test]
+    fn only_the_newest_snapshot_is_fresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = 100 * DAY;
+        let cache = cache_with(dir.path(), CacheSettings::default(), &[now - 2 * HOUR, now - 30 * 60]);
+        assert_eq!(cache.fresh(now).unwrap().unwrap().taken_at, now - 30 * 60);
+        assert_eq!(cache.fresh(now + HOUR).unwrap(), None);
+
+        let ttl = CacheSettings {
+            ttl_minutes: 0,
+            ..Default::default()
+        };
+        assert_eq!(SnapshotCache::new(dir.path(), ttl).fresh(now).unwrap(), None);
+    }
+
+    #[test]
+    fn prunes_by_count_and_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = 100 * DAY;
+        let settings = CacheSettings {
+            keep_last: 3,
+            keep_days: 7,
+            ..Default::default()
+        };
+        let cache = cache_with(dir.path(), settings, &[now - HOUR, now - 2 * HOUR, now - 3 * HOUR, now - 4 * HOUR]);
+        assert_eq!(taken(cache.prune(now).unwrap()), vec![now - 4 * HOUR]);
+
+        let old = cache_with(dir.path(), cache.settings.clone(), &[now - 8 * DAY]);
+        assert_eq!(taken(old.prune(now).unwrap()), vec![now - 8 * DAY]);
+        assert_eq!(cache.list().unwrap().len(), 3);
+
+        assert_eq!(cache.clear().unwrap(), 3);
+        assert!(cache.list().unwrap().is_empty());
+    }
+}
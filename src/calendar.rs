@@ -0,0 +1,113 @@
+// The mission's working hours, so time outside them doesn't count against an
+// area's contact time
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, TimeZone, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+/// Daily hours, with different hours (or `null` for none) on some weekdays
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkingHours {
+    #[serde(flatten)]
+    pub daily: Hours,
+    /// e.g. `{ "Mon": { "start": "18:00:00", "end": "21:30:00" } }` for P-day evenings
+    #[serde(default)]
+    pub weekdays: HashMap<Weekday, Option<Hours>>,
+}
+
+impl WorkingHours {
+    /// None on a day off. Hours that end before they start are treated as a day off.
+    pub fn hours_on(&self, day: NaiveDate) -> Option<Hours> {
+        let hours = match self.weekdays.get(&day.weekday()) {
+            Some(hours) => (*hours)?,
+            None => self.daily,
+        };
+        (hours.start < hours.end).then_some(hours)
+    }
+
+    /// Minutes between `from` and `to` that fall within working hours, in
+    /// `from`'s time zone
+    pub fn working_minutes(&self, from: DateTime<Tz>, to: DateTime<Tz>) -> usize {
+        let tz = from.timezone();
+        let to = to.with_timezone(&tz);
+        let mut minutes = 0;
+        let mut day = from.date_naive();
+        while day <= to.date_naive() {
+            let window = self.hours_on(day).and_then(|hours| Some((at(&tz, day, hours.start)?, at(&tz, day, hours.end)?)));
+            if let Some((start, end)) = window {
+                let start = start.max(from);
+                let end = end.min(to);
+                if end > start {
+                    minutes += end.signed_duration_since(start).num_minutes();
+                }
+            }
+            day += Duration::days(1);
+        }
+        minutes as usize
+    }
+}
+
+/// A wall clock time on `day`. Times skipped by a daylight saving change move
+/// to the hour after.
+fn at(tz: &Tz, day: NaiveDate, time: NaiveTime) -> Option<DateTime<Tz>> {
+    let local = day.and_time(time);
+    tz.from_local_datetime(&local)
+        .earliest()
+        .or_else(|| tz.from_local_datetime(&(local + Duration::hours(1))).earliest())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+    use chrono_tz::America::New_York;
+
+    fn at(date: &str) -> DateTime<Tz> {
+        let local = NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap();
+        New_York.from_local_datetime(&local).unwrap()
+    }
+
+    fn calendar() -> WorkingHours {
+        serde_json::from_value(serde_json::json!({
+            "start": "09:00:00",
+            "end": "21:30:00",
+            "weekdays": { "Mon": { "start": "18:00:00", "end": "21:30:00" }, "Sun": null },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn counts_only_working_time() {
+        let calendar = calendar();
+        // Thursday 9:30pm to Friday 9am is all after hours
+        assert_eq!(calendar.working_minutes(at("2024-03-07 21:30"), at("2024-03-08 09:00")), 0);
+        assert_eq!(calendar.working_minutes(at("2024-03-07 21:00"), at("2024-03-08 10:00")), 90);
+        // Saturday evening to Monday evening skips Sunday and Monday's P-day
+        assert_eq!(calendar.working_minutes(at("2024-03-09 21:00"), at("2024-03-11 19:00")), 90);
+        assert_eq!(calendar.working_minutes(at("2024-03-08 10:00"), at("2024-03-08 09:00")), 0);
+        assert_eq!(calendar.hours_on(at("2024-03-10 12:00").date_naive()), None);
+    }
+
+    #[test]
+    fn daylight_saving_changes_are_real_time() {
+        let mut calendar = calendar();
+        let night = Hours {
+            start: NaiveTime::from_hms_opt(0, 30, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(3, 30, 0).unwrap(),
+        };
+        calendar.weekdays.insert(Weekday::Sun, Some(night));
+        // 2am to 3am didn't happen on 10 March 2024 in New York
+        assert_eq!(calendar.working_minutes(at("2024-03-10 00:00"), at("2024-03-10 04:00")), 120);
+        // and 1am to 2am happened twice on 3 November
+        let start = at("2024-11-03 00:00");
+        assert_eq!(calendar.working_minutes(start, start + Duration::hours(5)), 240);
+    }
+}
@@ -1,44 +1,115 @@
 // Jackson Coxson
 // Code to interact with church servers
 
-use anyhow::Context;
-use chrono::NaiveDateTime;
+use chrono::{DateTime, Duration, Utc};
 use log::{info, warn};
 use reqwest::{redirect::Policy, Client};
 use reqwest_cookie_store::CookieStoreMutex;
 use serde::Deserialize;
 use serde_json::json;
+use tokio::sync::{Mutex, RwLock};
 use std::{
+    collections::HashSet,
     io::Write,
-    path::PathBuf,
-    str::FromStr,
+    path::{Path, PathBuf},
     sync::Arc,
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use crate::{bearer::BearerToken, env, persons};
+use crate::{
+    bearer::BearerToken,
+    cache::{SnapshotCache, TIMELINES_DIR},
+    env,
+    error::{check_status, Error, Result},
+    persons,
+    retry::Verdict,
+    settings::Settings,
+};
+use dialoguer::{theme::ColorfulTheme, Input};
+use totp_rs::{Algorithm, Secret, TOTP};
 
 pub const USER_AGENT: &str =
     "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/93.0.4577.82 Safari/537.36";
-const MAX_RETRIES: u8 = 3;
+/// Log in again when the bearer token expires within this many seconds
+const TOKEN_EXPIRY_SKEW_SECS: i64 = 60;
+/// Upper bound on IDX requests made after identifying during one login
+const MAX_IDX_STEPS: usize = 8;
+
+/// Where the church services live. Overridable so the client can be pointed
+/// at a mock server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceUrls {
+    /// Referral manager, which also serves the login page
+    pub referral_manager: String,
+    /// Okta identity provider for the IDX login flow
+    pub identity: String,
+}
+
+impl Default for ServiceUrls {
+    fn default() -> Self {
+        Self {
+            referral_manager: "https://referralmanager.churchofjesuschrist.org".to_string(),
+            identity: "https://id.churchofjesuschrist.org".to_string(),
+        }
+    }
+}
+
+impl ServiceUrls {
+    /// Production URLs, overridden by REFERRAL_MANAGER_URL and CHURCH_ID_URL when set
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let var = |key: &str, default: String| {
+            std::env::var(key)
+                .ok()
+                .filter(|url| !url.trim().is_empty())
+                .unwrap_or(default)
+                .trim_end_matches('/')
+                .to_string()
+        };
+        Self {
+            referral_manager: var("REFERRAL_MANAGER_URL", default.referral_manager),
+            identity: var("CHURCH_ID_URL", default.identity),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ChurchClient {
     http_client: Client,
     cookie_store: Arc<CookieStoreMutex>,
     pub env: env::Env,
-    bearer_token: Option<BearerToken>,
+    urls: ServiceUrls,
+    pub settings: Settings,
+    bearer_token: RwLock<Option<BearerToken>>,
+    /// Held while logging in, so requests refused at the same time share one login
+    login_lock: Mutex<()>,
+    /// GUIDs whose timelines are downloaded again even if they're cached
+    pub refresh: HashSet<String>,
     //pub holly_config: Option<crate::holly::config::Config>,
 }
 
 impl ChurchClient {
-    pub async fn new(env: env::Env) -> anyhow::Result<Self> {
+    pub async fn new(env: env::Env) -> Result<Self> {
+        Self::with_urls(env, ServiceUrls::from_env()).await
+    }
+
+    pub async fn with_urls(env: env::Env, urls: ServiceUrls) -> Result<Self> {
         // Check if the bearer token exists
-        let bearer_path = PathBuf::from_str(&env.working_path)?.join("bearer.token");
-        let cookies_path = PathBuf::from_str(&env.working_path)?.join("cookies.json");
+        let bearer_path = PathBuf::from(&env.working_path).join("bearer.token");
+        let cookies_path = PathBuf::from(&env.working_path).join("cookies.json");
 
         let bearer_token = if let Ok(b) = std::fs::read_to_string(&bearer_path) {
-            Some(BearerToken::from_base64(b)?)
+            match BearerToken::from_base64(b) {
+                Ok(token) if token.is_expired(Duration::seconds(TOKEN_EXPIRY_SKEW_SECS)) => {
+                    info!("Saved bearer token has expired");
+                    None
+                }
+                Ok(token) => Some(token),
+                Err(e) => {
+                    warn!("Ignoring the saved bearer token: {e}");
+                    None
+                }
+            }
         } else {
             info!("No bearer token saved");
             None
@@ -49,14 +120,16 @@ impl ChurchClient {
             std::fs::write(&cookies_path, "".as_bytes())?;
         }
         let cookie_store = {
-            let file = std::fs::File::open(&cookies_path)
-                .map(std::io::BufReader::new)
-                .unwrap();
+            let file = std::fs::File::open(&cookies_path).map(std::io::BufReader::new)?;
             // use re-exported version of `CookieStore` for crate compatibility
-            reqwest_cookie_store::CookieStore::load_json(file).unwrap()
+            reqwest_cookie_store::CookieStore::load_json(file).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable cookies: {e}");
+                reqwest_cookie_store::CookieStore::default()
+            })
         };
         let cookie_store = reqwest_cookie_store::CookieStoreMutex::new(cookie_store);
         let cookie_store = std::sync::Arc::new(cookie_store);
+        let settings = Settings::load(Path::new(&env.working_path))?;
 
         let http_client = Client::builder()
             .user_agent(USER_AGENT)
@@ -78,46 +151,92 @@ impl ChurchClient {
             http_client,
             cookie_store,
             env,
-            bearer_token,
+            urls,
+            settings,
+            bearer_token: RwLock::new(bearer_token),
+            login_lock: Mutex::new(()),
+            refresh: HashSet::new(),
             //holly_config,
         })
     }
 
-    pub async fn save_cookies(&self) -> anyhow::Result<()> {
+    pub async fn save_cookies(&self) -> Result<()> {
         info!("Saving cookies");
-        let cookies_path = PathBuf::from_str(&self.env.working_path)?.join("cookies.json");
-        let mut writer = std::fs::File::create(&cookies_path)
-            .map(std::io::BufWriter::new)
-            .unwrap();
+        let cookies_path = PathBuf::from(&self.env.working_path).join("cookies.json");
+        let mut writer = std::fs::File::create(&cookies_path).map(std::io::BufWriter::new)?;
         let store = self.cookie_store.lock().unwrap();
         store
             .save_incl_expired_and_nonpersistent_json(&mut writer)
-            .unwrap();
+            .map_err(|e| Error::CacheIo(std::io::Error::other(e.to_string())))?;
         Ok(())
     }
 
-    async fn write_bearer_token(&self, token: &str) -> anyhow::Result<()> {
+    async fn write_bearer_token(&self, token: &str) -> Result<()> {
         info!("Saving bearer token");
-        let bearer_path = PathBuf::from_str(&self.env.working_path)?.join("bearer.token");
-        let mut writer = std::fs::File::create(&bearer_path)
-            .map(std::io::BufWriter::new)
-            .unwrap();
+        let bearer_path = PathBuf::from(&self.env.working_path).join("bearer.token");
+        let mut writer = std::fs::File::create(&bearer_path).map(std::io::BufWriter::new)?;
         writer.write_all(token.as_bytes())?;
         Ok(())
     }
 
     /// Logs into churchofjesuschrist.org
-    pub async fn login(&mut self) -> anyhow::Result<BearerToken> {
+    pub async fn login(&self) -> Result<BearerToken> {
+        let _login = self.login_lock.lock().await;
+        self.login_with_retry().await
+    }
+
+    /// Logs in again unless another request already has since `stale` was the token in use
+    async fn relogin(&self, stale: Option<&str>) -> Result<BearerToken> {
+        let _login = self.login_lock.lock().await;
+        if let Some(token) = self.bearer_token.read().await.as_ref() {
+            if Some(token.token.as_str()) != stale && !token.is_expired(Duration::seconds(TOKEN_EXPIRY_SKEW_SECS)) {
+                info!("Already logged in again by another request");
+                return Ok(token.clone());
+            }
+        }
+        self.login_with_retry().await
+    }
+
+    /// The bearer token, logging in first when there's none or it's about to expire
+    async fn fresh_token(&self) -> Result<BearerToken> {
+        if let Some(token) = self.bearer_token.read().await.as_ref() {
+            if !token.is_expired(Duration::seconds(TOKEN_EXPIRY_SKEW_SECS)) {
+                return Ok(token.clone());
+            }
+            info!("Bearer token expired, logging in again");
+        }
+        self.relogin(None).await
+    }
+
+    /// Runs the login, starting over when the servers are busy or unreachable
+    async fn login_with_retry(&self) -> Result<BearerToken> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let error = match self.try_login().await {
+                Ok(token) => return Ok(token),
+                Err(e) => e,
+            };
+            // Logging in again won't fix a failed login, so only backoff is on the table
+            match self.settings.retry.classify(&error, attempt, true) {
+                Verdict::Retry(delay) => {
+                    warn!("Login failed, trying again in {delay:?}: {error}");
+                    tokio::time::sleep(delay).await;
+                }
+                _ => return Err(error),
+            }
+        }
+    }
+
+    async fn try_login(&self) -> Result<BearerToken> {
         info!("Logging into referral manager");
-        self.cookie_store.lock().unwrap().clear();
+        // Only the identity provider's session is started over. Other requests may still
+        // be using referral manager's cookies, which this login replaces when it succeeds.
+        clear_identity_cookies(&mut self.cookie_store.lock().unwrap(), &self.urls.identity);
 
         // Get the inital login page
         info!("Loading the initial login page");
-        let res = self
-            .http_client
-            .get("https://referralmanager.churchofjesuschrist.org")
-            .send()
-            .await?
+        let res = check_status(self.http_client.get(&self.urls.referral_manager).send().await?)?
             .text()
             .await?;
 
@@ -127,22 +246,22 @@ impl ChurchClient {
 
         let start_index = res
             .find(start_token)
-            .ok_or_else(|| anyhow::anyhow!("stateToken not found in response"))?
+            .ok_or_else(|| Error::SchemaDrift("stateToken not found in the login page".to_string()))?
             + start_token.len();
 
         let end_index = res[start_index..]
             .find(end_token)
-            .ok_or_else(|| anyhow::anyhow!("End token not found in response"))?
+            .ok_or_else(|| Error::SchemaDrift("End of the stateToken not found in the login page".to_string()))?
             + start_index;
 
         // Ensure the indices are valid
         if start_index >= end_index {
-            return Err(anyhow::anyhow!("Invalid indices for stateToken extraction"));
+            return Err(Error::SchemaDrift("Invalid indices for stateToken extraction".to_string()));
         }
 
         let state_token = &res[start_index..end_index];
-        let state_token = decode_escape_sequences(state_token)?;
-        let state_token: String = serde_json::from_str(&format!("\"{state_token}\"")).unwrap();
+        let state_token = decode_escape_sequences(state_token);
+        let state_token: String = serde_json::from_str(&format!("\"{state_token}\""))?;
 
         #[derive(Deserialize)]
         struct StateHandle {
@@ -151,17 +270,18 @@ impl ChurchClient {
         }
         // Trade the state token for the state handle
         info!("Trading the token for the state handle");
-        let state_handle = self
-            .http_client
-            .post("https://id.churchofjesuschrist.org/idp/idx/introspect")
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .body(format!("{{\"stateToken\": \"{state_token}\"}}"))
-            .send()
-            .await?
-            .json::<StateHandle>()
-            .await?
-            .state_handle;
+        let state_handle = check_status(
+            self.http_client
+                .post(format!("{}/idp/idx/introspect", self.urls.identity))
+                .header("Content-Type", "application/json")
+                .header("Accept", "application/json")
+                .body(format!("{{\"stateToken\": \"{state_token}\"}}"))
+                .send()
+                .await?,
+        )?
+        .json::<StateHandle>()
+        .await?
+        .state_handle;
 
         // Send the username
         info!("Sending the username");
@@ -172,259 +292,275 @@ impl ChurchClient {
         .to_string();
 
         // Send the username to get the state handle
-        let identify_response: serde_json::Value = self
-            .http_client
-            .post("https://id.churchofjesuschrist.org/idp/idx/identify")
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .body(body)
-            .send()
-            .await?
-            .json()
-            .await?;
-
-        // Extract the state handle from the response
-        let state_handle = identify_response["stateHandle"]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("No state handle in identify response"))?
-            .to_string();
+        let mut response = self.idx_post("/idp/idx/identify", body).await?;
 
-        // Find password authenticator id
-        // Authenticators: Array [Object {"allowedFor": String("any"), "displayName": String("Email"), "id": String("---"), "key": String("okta_email"), "methods": Array [Object {"type": String("email")}], "type": String("email")}, Object {"allowedFor": String("sso"), "displayName": String("Password"), "id": String("---"), "key": String("okta_password"), "methods": Array [Object {"type": String("password")}], "type": String("password")}]
-        let password_authenticator_id = identify_response["authenticators"]["value"]
-            .as_array()
-            .and_then(|authenticators| {
-                authenticators.iter().find_map(|auth| {
-                    if auth["type"] == "password" {
-                        Some(auth["id"].as_str()?.to_string())
-                    } else {
-                        None
+        // Walk the remediation chain until Okta hands back a success href.
+        // Each authenticator (password, email code, TOTP) is answered at most once.
+        let mut answered: Vec<String> = Vec::new();
+        let mut steps = 0;
+        let success_href = loop {
+            steps += 1;
+            if steps > MAX_IDX_STEPS {
+                return Err(Error::AuthenticationFailed("Too many authentication steps".to_string()));
+            }
+            match next_idx_step(&response, &answered, self.env.totp_seed.is_some())? {
+                IdxStep::Done(href) => break href,
+                IdxStep::Select(authenticator) => {
+                    let state_handle = idx_state_handle(&response)?;
+                    info!("Challenging the {} authenticator", authenticator.kind);
+                    let mut selected = json!({ "id": authenticator.id });
+                    if let Some(method) = authenticator.method_type() {
+                        selected["methodType"] = json!(method);
                     }
-                })
-            })
-            .ok_or_else(|| anyhow::anyhow!("No password authenticator found"))?;
-
-        // Challenge the password authenticator
-        info!("Challenging the password authenticator");
-        let body = json!({
-            "authenticator": {
-                "id": password_authenticator_id
-            },
-            "stateHandle": state_handle
-        })
-        .to_string();
-        let challenge_response: serde_json::Value = self
-            .http_client
-            .post("https://id.churchofjesuschrist.org/idp/idx/challenge")
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .body(body)
-            .send()
-            .await?
-            .json()
-            .await?;
-
-        // Extract the state handle from the challenge response
-        let state_handle = challenge_response["stateHandle"]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("No state handle in challenge response"))?
-            .to_string();
-
-        // Send the password
-        #[derive(Deserialize)]
-        struct PasswordResponse {
-            success: SuccessResponse,
-        }
-
-        #[derive(Deserialize)]
-        struct SuccessResponse {
-            href: String,
-        }
-
-        info!("Sending the password");
-        let body = json!({
-            "stateHandle": state_handle,
-            "credentials": {
-                "passcode": self.env.church_password
+                    let body = json!({
+                        "authenticator": selected,
+                        "stateHandle": state_handle
+                    })
+                    .to_string();
+                    response = self.idx_post("/idp/idx/challenge", body).await?;
+                }
+                IdxStep::Answer(authenticator) => {
+                    let state_handle = idx_state_handle(&response)?;
+                    info!("Answering the {} authenticator", authenticator.kind);
+                    let body = json!({
+                        "stateHandle": state_handle,
+                        "credentials": {
+                            "passcode": self.authenticator_passcode(&authenticator).await?
+                        }
+                    })
+                    .to_string();
+                    answered.push(authenticator.id);
+                    response = self.idx_post("/idp/idx/challenge/answer", body).await?;
+                }
             }
-        })
-        .to_string();
-        let challenge_answer_response = self
-            .http_client
-            .post("https://id.churchofjesuschrist.org/idp/idx/challenge/answer")
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .body(body)
-            .send()
-            .await?
-            .json::<PasswordResponse>()
-            .await?;
+        };
 
         // Set cookies
         info!("Getting the success href");
-        self.http_client
-            .get(challenge_answer_response.success.href)
-            .send()
-            .await?;
+        check_status(self.http_client.get(success_href).send().await?)?;
 
         // Get the bearer token
         info!("Getting the bearer token");
-        let token = self
-            .http_client
-            .get("https://referralmanager.churchofjesuschrist.org/services/auth")
-            .header("Accept", "application/json")
-            .send()
-            .await?
-            .json::<serde_json::Value>()
-            .await?["token"]
+        let token = check_status(
+            self.http_client
+                .get(format!("{}/services/auth", self.urls.referral_manager))
+                .header("Accept", "application/json")
+                .send()
+                .await?,
+        )?
+        .json::<serde_json::Value>()
+        .await?["token"]
             .clone();
         let token = (match token {
             serde_json::Value::String(t) => Ok(t),
-            _ => Err(anyhow::anyhow!("No token in response json")),
+            _ => Err(Error::SchemaDrift("No token in the auth response".to_string())),
         })?;
 
         self.save_cookies().await?;
         self.write_bearer_token(&token).await?;
 
-        let token = BearerToken::from_base64(token)?;
-        self.bearer_token = Some(token.clone());
+        let token = BearerToken::from_base64(token).map_err(|e| Error::SchemaDrift(e.to_string()))?;
+        *self.bearer_token.write().await = Some(token.clone());
 
         Ok(token)
     }
 
-    /// Gets the list of everyone from the referral manager. This is a HUGE request at roughly 8mb in the CSDM
-    pub async fn get_people_list(&mut self) -> anyhow::Result<Vec<persons::Person>> {
-        info!("Getting the people list from referral manager");
-        let mut tries = 0;
+    /// POSTs a JSON body to an Okta IDX endpoint
+    /// Okta answers rejected credentials with a 4xx and the usual IDX body,
+    /// so the body is returned whatever the status and read by [`next_idx_step`].
+    async fn idx_post(&self, path: &str, body: String) -> Result<serde_json::Value> {
+        let res = self
+            .http_client
+            .post(format!("{}{path}", self.urls.identity))
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json")
+            .body(body)
+            .send()
+            .await?;
+        let status = res.status();
+        if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(check_status(res).unwrap_err());
+        }
+        Ok(res.json().await?)
+    }
+
+    /// Gets the passcode for an authenticator, prompting when it can't be answered unattended
+    async fn authenticator_passcode(&self, authenticator: &Authenticator) -> Result<String> {
+        match authenticator.kind.as_str() {
+            "password" => Ok(self.env.church_password.clone()),
+            "app" if authenticator.accepts_totp() && self.env.totp_seed.is_some() => {
+                totp_code(self.env.totp_seed.as_deref().unwrap_or_default())
+            }
+            "app" => prompt_code("Enter the code from your authenticator app").await,
+            "email" => prompt_code("Enter the verification code emailed to you").await,
+            "phone" => prompt_code("Enter the verification code texted to you").await,
+            other => Err(Error::MfaRequired(format!("Unsupported authenticator type {other}"))),
+        }
+    }
 
-        while tries < MAX_RETRIES {
-            let token = match &self.bearer_token {
-                Some(t) => t,
-                None => &self.login().await?,
+    /// GETs JSON from referral manager, retrying as [`Settings::retry`] says.
+    /// With `bearer` the bearer token is sent, otherwise the session cookies are enough.
+    async fn get_json(&self, url: &str, bearer: bool) -> Result<serde_json::Value> {
+        let mut attempt = 0;
+        let mut reauthenticated = false;
+        loop {
+            attempt += 1;
+            let mut request = self.http_client.get(url);
+            // The token in use when the request went out, so a refusal only logs in again once
+            let token = if bearer {
+                let token = self.fresh_token().await?;
+                request = request.header("Authorization", format!("Bearer {}", token.token));
+                Some(token)
+            } else {
+                self.bearer_token.read().await.clone()
             };
-            tries += 1;
-            if
-                let Ok(list) = self.http_client
-                    .get(
-                        format!(
-                            "https://referralmanager.churchofjesuschrist.org/services/people/mission/{}?includeDroppedPersons=true",
-                            token.claims.mission_id
-                        )
-                    )
-                    .header("Authorization", format!("Bearer {}", token.token))
-                    .send().await
-            {
-                if let Ok(list) = list.json::<serde_json::Value>().await {
-                    let list = persons::Person::parse_lossy(list);
-                    info!("Received {} people from referral manager", list.len());
-                    return Ok(list);
-                } else {
-                    warn!("Getting the people list failed at JSON parse");
-                    self.bearer_token = None;
+            let result = match request.send().await {
+                Ok(res) => match check_status(res) {
+                    Ok(res) => res.json::<serde_json::Value>().await.map_err(Error::from),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e.into()),
+            };
+            let error = match result {
+                Ok(json) => return Ok(json),
+                Err(e) => e,
+            };
+            match self.settings.retry.classify(&error, attempt, reauthenticated) {
+                Verdict::Retry(delay) => {
+                    warn!("Request to {url} failed, trying again in {delay:?}: {error}");
+                    tokio::time::sleep(delay).await;
                 }
-            } else {
-                warn!("Getting the people list failed at the request");
-                self.bearer_token = None;
+                Verdict::Reauthenticate => {
+                    warn!("Request to {url} was refused, logging in again: {error}");
+                    reauthenticated = true;
+                    self.relogin(token.as_ref().map(|t| t.token.as_str())).await?;
+                }
+                Verdict::Fail => return Err(error),
             }
         }
-        Err(anyhow::anyhow!("Max tries exceeded"))
+    }
+
+    /// Gets the list of everyone from the referral manager. This is a HUGE request at roughly 8mb in the CSDM
+    pub async fn get_people_list(&self) -> Result<Vec<persons::Person>> {
+        info!("Getting the people list from referral manager");
+        let mission_id = self.fresh_token().await?.claims.mission_id;
+        let url = format!(
+            "{}/services/people/mission/{mission_id}?includeDroppedPersons=true",
+            self.urls.referral_manager
+        );
+        let list = persons::Person::parse_lossy(self.get_json(&url, true).await?);
+        info!("Received {} people from referral manager", list.len());
+        Ok(list)
     }
 
     /// Gets a cached list from referral manager to save trips to church servers.
-    /// A cache will be considered 'hit' if the list is less than an hour old.
-    pub async fn get_cached_people_list(&mut self) -> anyhow::Result<Vec<persons::Person>> {
-        let lists_path = PathBuf::from_str(&self.env.working_path)?.join("people_lists");
-        std::fs::create_dir_all(&lists_path)?;
-
-        let now = SystemTime::now();
-        let now = now
-            .duration_since(UNIX_EPOCH)
-            .context("Your clock is wrong")?
-            .as_secs();
-
-        // Read all the entries in the cache
-        for f in std::fs::read_dir(&lists_path)? {
-            match f {
-                Ok(f) if f.file_type()?.is_file() => {
-                    if let Ok(file_name) = f.file_name().into_string() {
-                        if let Some(file_name) = file_name.split_once('.') {
-                            if let Ok(timestamp) = file_name.0.parse::<u64>() {
-                                if let Some(diff) = now.checked_sub(timestamp) {
-                                    if diff < 60 * 60 {
-                                        info!("Cache hit");
-                                        return Ok(persons::Person::parse_lossy(
-                                            serde_json::from_str(
-                                                &std::fs::read_to_string(f.path()).unwrap(),
-                                            )?,
-                                        ));
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                _ => (),
-            }
+    /// The newest snapshot is used while it's younger than the cache TTL.
+    pub async fn get_cached_people_list(&self) -> Result<Vec<persons::Person>> {
+        let cache = SnapshotCache::new(Path::new(&self.env.working_path), self.settings.cache.clone());
+        let now = unix_now()?;
+
+        if let Some(snapshot) = cache.fresh(now)? {
+            info!("Cache hit");
+            return cache.load(&snapshot);
         }
         info!("Cache miss");
         let list = self.get_people_list().await?;
-        let file = std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(lists_path.join(format!("{now}.json")))?;
-        serde_json::to_writer(file, &json!({"persons": &list}))?;
+        cache.save(now, &list)?;
+        // Old snapshots left behind don't stop a run
+        match cache.prune(now) {
+            Ok(pruned) => {
+                for snapshot in pruned {
+                    info!("Pruned the people list snapshot {:?}", snapshot.path);
+                }
+            }
+            Err(e) => warn!("Unable to prune old people list snapshots: {e}"),
+        }
         Ok(list)
     }
 
     pub async fn get_person_timeline(
-        &mut self,
+        &self,
         person: &persons::Person,
-    ) -> anyhow::Result<Vec<persons::TimelineEvent>> {
+    ) -> Result<Vec<persons::TimelineEvent>> {
         info!("Getting timeline for {}", person.guid);
-        let mut tries = 0;
-
-        while tries < MAX_RETRIES {
-            tries += 1;
-            if let Ok(list) = self
-                .http_client
-                .get(format!(
-                    "https://referralmanager.churchofjesuschrist.org/services/progress/timeline/{}",
-                    person.guid
-                ))
-                .send()
-                .await
-            {
-                if let Ok(list) = list.json::<serde_json::Value>().await {
-                    let mut list: Vec<persons::TimelineEvent> =
-                        persons::TimelineEvent::parse_lossy(list);
-
-                    //Apply MST to EST conversion for each event
-                    for event in &mut list {
-                        event.convert_mst_to_est();
-                    }
-
-                    info!(
-                        "Received {} timeline events from referral manager",
-                        list.len()
-                    );
-                    return Ok(list);
-                } else {
-                    warn!("Getting the timeline events list failed at JSON parse");
-                    self.login().await?;
+        let list = match self.cached_timeline(&person.guid)? {
+            Some(list) => list,
+            None => {
+                let url = format!(
+                    "{}/services/progress/timeline/{}",
+                    self.urls.referral_manager, person.guid
+                );
+                let list = self.get_json(&url, false).await?;
+                if !list.is_array() {
+                    return Err(Error::SchemaDrift(format!("Timeline for {} is not a list", person.guid)));
                 }
-            } else {
-                warn!("Getting the timeline events list failed at the request");
-                self.login().await?;
+                // The cache is only a shortcut, so the timeline is still used if it can't be saved
+                if let Err(e) = self.cache_timeline(&person.guid, &list) {
+                    warn!("Couldn't cache the timeline for {}: {e}", person.guid);
+                }
+                list
+            }
+        };
+        let list: Vec<persons::TimelineEvent> = persons::TimelineEvent::parse_lossy(list);
+
+        info!(
+            "Received {} timeline events from referral manager",
+            list.len()
+        );
+        Ok(list)
+    }
+
+    /// Where a person's timeline is cached, if their GUID is safe to use as a file name
+    fn timeline_cache_path(&self, guid: &str) -> Option<PathBuf> {
+        if guid.is_empty() || !guid.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return None;
+        }
+        Some(PathBuf::from(&self.env.working_path).join(TIMELINES_DIR).join(format!("{guid}.json")))
+    }
+
+    /// Reads a cached timeline, as referral manager sent it, if it's younger than the TTL
+    fn cached_timeline(&self, guid: &str) -> Result<Option<serde_json::Value>> {
+        if self.settings.timeline_ttl_minutes == 0 || self.refresh.contains(guid) {
+            return Ok(None);
+        }
+        let Some(path) = self.timeline_cache_path(guid).filter(|p| p.is_file()) else {
+            return Ok(None);
+        };
+        let cached: CachedTimeline = match serde_json::from_str(&std::fs::read_to_string(&path)?) {
+            Ok(cached) => cached,
+            Err(e) => {
+                warn!("Ignoring the damaged cached timeline {path:?}: {e}");
+                return Ok(None);
             }
+        };
+        match unix_now()?.checked_sub(cached.fetched_at) {
+            Some(age) if age < self.settings.timeline_ttl_minutes * 60 => {
+                info!("Timeline cache hit for {guid}");
+                Ok(Some(cached.timeline))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn cache_timeline(&self, guid: &str, timeline: &serde_json::Value) -> Result<()> {
+        let Some(path) = self.timeline_cache_path(guid) else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
         }
-        Err(anyhow::anyhow!("Max tries exceeded"))
+        let cached = CachedTimeline {
+            fetched_at: unix_now()?,
+            timeline: timeline.clone(),
+        };
+        std::fs::write(path, serde_json::to_vec(&cached)?)?;
+        Ok(())
     }
 
     pub async fn get_person_last_contact(
-        &mut self,
+        &self,
         person: &persons::Person,
-    ) -> anyhow::Result<Option<NaiveDateTime>> {
+    ) -> Result<Option<DateTime<Utc>>> {
         let timeline = self.get_person_timeline(person).await?;
         for item in timeline {
             match item.item_type {
@@ -441,46 +577,193 @@ impl ChurchClient {
         }
         Ok(None)
     }
+}
 
-    pub async fn get_person_contact_time(
-        &mut self,
-        person: &persons::Person,
-    ) -> anyhow::Result<Option<usize>> {
-        let mut timeline = self.get_person_timeline(person).await?;
-        timeline.reverse();
+/// A timeline saved in `working_path/timelines`
+#[derive(Deserialize, serde::Serialize)]
+struct CachedTimeline {
+    /// Unix seconds
+    fetched_at: u64,
+    timeline: serde_json::Value,
+}
 
-        let mut referral_sent = None;
-        let mut last_contact = None;
+/// Drops the cookies set by the identity provider's host, so a login starts a new session there
+fn clear_identity_cookies(store: &mut reqwest_cookie_store::CookieStore, identity: &str) {
+    let Some(host) = reqwest::Url::parse(identity).ok().and_then(|url| url.host_str().map(str::to_string)) else {
+        return;
+    };
+    let stale: Vec<(String, String, String)> = store
+        .iter_any()
+        .filter(|cookie| String::from(&cookie.domain) == host)
+        .map(|cookie| (host.clone(), String::from(&cookie.path), cookie.name().to_string()))
+        .collect();
+    for (domain, path, name) in stale {
+        store.remove(&domain, &path, &name);
+    }
+}
 
-        for item in timeline {
-            match item.item_type {
-                persons::TimelineItemType::NewReferral => {
-                    referral_sent = Some(item.item_date);
-                    last_contact = None;
-                }
-                persons::TimelineItemType::Contact | persons::TimelineItemType::Teaching => {
-                    if last_contact.is_none() {
-                        last_contact = Some(item.item_date);
-                    }
-                }
-                _ => {
-                    continue;
-                }
-            }
+fn unix_now() -> Result<u64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| anyhow::anyhow!("Your clock is wrong"))?
+        .as_secs())
+}
+
+/// An Okta authenticator as listed in an IDX response
+#[derive(Clone, Debug, PartialEq)]
+struct Authenticator {
+    id: String,
+    /// `password`, `email`, `app`, `phone`, ...
+    kind: String,
+    /// `okta_password`, `okta_email`, `google_otp`, `okta_verify`, ...
+    key: String,
+    methods: Vec<String>,
+}
+
+impl Authenticator {
+    fn from_value(value: &serde_json::Value) -> Option<Self> {
+        Some(Self {
+            id: value["id"].as_str()?.to_string(),
+            kind: value["type"].as_str()?.to_string(),
+            key: value["key"].as_str().unwrap_or_default().to_string(),
+            methods: value["methods"]
+                .as_array()
+                .map(|methods| {
+                    methods
+                        .iter()
+                        .filter_map(|m| m["type"].as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
+
+    fn accepts_totp(&self) -> bool {
+        self.key == "google_otp" || self.methods.iter().any(|m| m == "totp" || m == "otp")
+    }
+
+    /// The `methodType` to send when selecting this authenticator
+    fn method_type(&self) -> Option<&str> {
+        match self.kind.as_str() {
+            "password" => None,
+            "app" => self
+                .methods
+                .iter()
+                .find(|m| *m == "totp" || *m == "otp")
+                .or(self.methods.first())
+                .map(String::as_str),
+            _ => self.methods.first().map(String::as_str),
         }
+    }
+}
 
-        if let Some(referral_sent) = referral_sent {
-            if let Some(last_contact) = last_contact {
-                let duration = last_contact.signed_duration_since(referral_sent);
-                return Ok(Some(duration.num_minutes() as usize));
-            }
+#[derive(Debug, PartialEq)]
+enum IdxStep {
+    /// Pick this authenticator with `/idp/idx/challenge`
+    Select(Authenticator),
+    /// Send a passcode for this authenticator to `/idp/idx/challenge/answer`
+    Answer(Authenticator),
+    /// Logged in, follow this href to set the session cookies
+    Done(String),
+}
+
+fn idx_state_handle(response: &serde_json::Value) -> Result<String> {
+    Ok(response["stateHandle"]
+        .as_str()
+        .ok_or_else(|| Error::SchemaDrift("No state handle in IDX response".to_string()))?
+        .to_string())
+}
+
+/// Works out what the IDX remediation chain wants next from the last response
+fn next_idx_step(
+    response: &serde_json::Value,
+    answered: &[String],
+    has_totp_seed: bool,
+) -> Result<IdxStep> {
+    if let Some(href) = response["success"]["href"].as_str() {
+        return Ok(IdxStep::Done(href.to_string()));
+    }
+
+    let errors: Vec<&str> = response["messages"]["value"]
+        .as_array()
+        .map(|messages| {
+            messages
+                .iter()
+                .filter(|m| m["class"] == "ERROR")
+                .filter_map(|m| m["message"].as_str())
+                .collect()
+        })
+        .unwrap_or_default();
+    if !errors.is_empty() {
+        return Err(Error::AuthenticationFailed(errors.join(" ")));
+    }
+
+    let remediations: Vec<&str> = response["remediation"]["value"]
+        .as_array()
+        .map(|r| r.iter().filter_map(|r| r["name"].as_str()).collect())
+        .unwrap_or_default();
+
+    if remediations.contains(&"challenge-authenticator") {
+        let current = [&response["currentAuthenticatorEnrollment"], &response["currentAuthenticator"]]
+            .into_iter()
+            .find_map(|c| Authenticator::from_value(&c["value"]))
+            .ok_or_else(|| Error::SchemaDrift("No current authenticator in challenge response".to_string()))?;
+        if answered.contains(&current.id) {
+            return Err(Error::AuthenticationFailed(format!("The {} authenticator was not accepted", current.kind)));
         }
-        Ok(None)
+        return Ok(IdxStep::Answer(current));
+    }
+
+    // Older responses list the authenticators without a remediation block
+    if remediations.is_empty() || remediations.contains(&"select-authenticator-authenticate") {
+        let rank = |a: &Authenticator| match a.kind.as_str() {
+            "password" => 0,
+            "app" if has_totp_seed && a.accepts_totp() => 1,
+            "email" => 2,
+            "app" => 3,
+            _ => 4,
+        };
+        return response["authenticators"]["value"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Authenticator::from_value)
+            .filter(|a| !answered.contains(&a.id))
+            .min_by_key(rank)
+            .map(IdxStep::Select)
+            .ok_or_else(|| Error::MfaRequired("No usable authenticator found".to_string()));
     }
+
+    Err(Error::SchemaDrift(format!("Unexpected login step: {}", remediations.join(", "))))
+}
+
+/// Generates the current TOTP code from a base32 seed
+fn totp_code(seed: &str) -> Result<String> {
+    let seed: String = seed.chars().filter(|c| !c.is_whitespace()).collect();
+    let secret = Secret::Encoded(seed.to_uppercase())
+        .to_bytes()
+        .map_err(|e| Error::MfaRequired(format!("TOTP_SEED is not valid base32: {e:?}")))?;
+    TOTP::new_unchecked(Algorithm::SHA1, 6, 1, 30, secret)
+        .generate_current()
+        .map_err(|_| anyhow::anyhow!("Your clock is wrong").into())
+}
+
+/// Asks for a verification code. Without a terminal to ask on, the login can't go on.
+/// The prompt runs on a blocking thread so waiting for the user doesn't hold up other tasks.
+async fn prompt_code(prompt: &'static str) -> Result<String> {
+    let code = tokio::task::spawn_blocking(move || {
+        Input::<String>::with_theme(&ColorfulTheme::default())
+            .with_prompt(prompt)
+            .interact()
+    })
+    .await
+    .map_err(|e| Error::MfaRequired(format!("{prompt}: {e}")))?
+    .map_err(|e| Error::MfaRequired(format!("{prompt}: {e}")))?;
+    Ok(code.trim().to_string())
 }
 
 /// Function to decode escape sequences including \xNN
-fn decode_escape_sequences(s: &str) -> anyhow::Result<String> {
+fn decode_escape_sequences(s: &str) -> String {
     // Replace URL encoded sequences
     let decoded_string = s
         .replace("\\x2D", "-") // Replace \x2D with '-'
@@ -489,5 +772,309 @@ fn decode_escape_sequences(s: &str) -> anyhow::Result<String> {
         .replace("\\x2F", "/") // Replace \x2F with '/'
         .replace("\\x3D", "="); // Replace \x3D with '='
 
-    Ok(decoded_string.to_string())
+    decoded_string.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authenticators() -> serde_json::Value {
+        json!({ "value": [
+            { "id": "aut-email", "type": "email", "key": "okta_email", "methods": [{ "type": "email" }] },
+            { "id": "aut-pass", "type": "password", "key": "okta_password", "methods": [{ "type": "password" }] },
+            { "id": "aut-totp", "type": "app", "key": "google_otp", "methods": [{ "type": "otp" }] }
+        ]})
+    }
+
+    fn select_response() -> serde_json::Value {
+        json!({
+            "stateHandle": "sh",
+            "remediation": { "value": [{ "name": "select-authenticator-authenticate" }] },
+            "authenticators": authenticators()
+        })
+    }
+
+    fn selected(step: IdxStep) -> String {
+        match step {
+            IdxStep::Select(a) => a.id,
+            other => panic!("expected a selection, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn password_is_selected_first() {
+        let step = next_idx_step(&select_response(), &[], false).unwrap();
+        assert_eq!(selected(step), "aut-pass");
+    }
+
+    #[test]
+    fn second_factor_prefers_totp_with_a_seed() {
+        let answered = vec!["aut-pass".to_string()];
+        let step = next_idx_step(&select_response(), &answered, true).unwrap();
+        assert_eq!(selected(step), "aut-totp");
+
+        let step = next_idx_step(&select_response(), &answered, false).unwrap();
+        assert_eq!(selected(step), "aut-email");
+    }
+
+    #[test]
+    fn challenge_answers_current_authenticator() {
+        let response = json!({
+            "stateHandle": "sh",
+            "remediation": { "value": [{ "name": "challenge-authenticator" }] },
+            "currentAuthenticatorEnrollment": { "value": authenticators()["value"][0] }
+        });
+        match next_idx_step(&response, &[], false).unwrap() {
+            IdxStep::Answer(a) => {
+                assert_eq!(a.kind, "email");
+                assert_eq!(a.method_type(), Some("email"));
+            }
+            other => panic!("expected an answer, got {other:?}"),
+        }
+
+        // The same challenge coming back means the code was refused
+        assert!(next_idx_step(&response, &["aut-email".to_string()], false).is_err());
+    }
+
+    #[test]
+    fn success_and_errors() {
+        let done = json!({ "success": { "href": "https://example.com/done" } });
+        assert_eq!(
+            next_idx_step(&done, &[], false).unwrap(),
+            IdxStep::Done("https://example.com/done".to_string())
+        );
+
+        let rejected = json!({
+            "stateHandle": "sh",
+            "messages": { "value": [{ "class": "ERROR", "message": "Password is incorrect" }] },
+            "remediation": { "value": [{ "name": "challenge-authenticator" }] }
+        });
+        let err = next_idx_step(&rejected, &[], false).unwrap_err();
+        assert!(err.to_string().contains("Password is incorrect"));
+    }
+
+    #[test]
+    fn login_only_clears_identity_cookies() {
+        let mut store = reqwest_cookie_store::CookieStore::default();
+        let identity = reqwest::Url::parse("https://id.example.org/idp").unwrap();
+        let referral_manager = reqwest::Url::parse("https://rm.example.org/").unwrap();
+        store.parse("idx=okta", &identity).unwrap();
+        store.parse("rm_session=abc", &referral_manager).unwrap();
+        clear_identity_cookies(&mut store, identity.as_str());
+        let names: Vec<&str> = store.iter_any().map(|cookie| cookie.name()).collect();
+        assert_eq!(names, vec!["rm_session"]);
+    }
+
+    #[test]
+    fn totp_codes_from_seed() {
+        let code = totp_code("gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap();
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+        assert!(totp_code("not base32!").is_err());
+    }
+
+    fn mock_people() -> (Vec<serde_json::Value>, Vec<(String, Vec<serde_json::Value>)>) {
+        use crate::mock::{event, person};
+        let assigned = chrono::Utc::now() - Duration::days(2);
+        let people = vec![person("guid-1", "Alex", "Riverside", assigned)];
+        let timelines = vec![(
+            "guid-1".to_string(),
+            vec![
+                event("CONTACT", assigned + Duration::hours(3), Some(true)),
+                event("NEW_REFERRAL", assigned, None),
+            ],
+        )];
+        (people, timelines)
+    }
+
+    #[tokio::test]
+    async fn logs_in_and_fetches_from_mock() {
+        let (people, timelines) = mock_people();
+        let mock = crate::mock::MockChurch::start(people, timelines, false).await;
+        let dir = tempfile::tempdir().unwrap();
+        let client = ChurchClient::with_urls(mock.env(dir.path()), mock.urls()).await.unwrap();
+
+        let token = client.login().await.unwrap();
+        assert_eq!(token.claims.mission_id, crate::mock::MISSION_ID);
+        assert!(dir.path().join("bearer.token").exists());
+
+        let list = client.get_people_list().await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].first_name, "Alex");
+
+        let timeline = client.get_person_timeline(&list[0]).await.unwrap();
+        assert_eq!(timeline.len(), 2);
+        assert_eq!(crate::scoring::contact_metrics(&timeline, &client.settings.scoring).first_attempt_minutes, Some(180));
+    }
+
+    #[tokio::test]
+    async fn logs_in_with_totp_second_factor() {
+        let (people, timelines) = mock_people();
+        let mock = crate::mock::MockChurch::start(people, timelines, true).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = mock.env(dir.path());
+        env.totp_seed = Some(crate::mock::TOTP_SEED.to_string());
+        let client = ChurchClient::with_urls(env, mock.urls()).await.unwrap();
+
+        client.login().await.unwrap();
+        let challenges = mock
+            .requested_paths()
+            .await
+            .into_iter()
+            .filter(|p| p == "/idp/idx/challenge/answer")
+            .count();
+        assert_eq!(challenges, 2);
+    }
+
+    #[tokio::test]
+    async fn wrong_password_is_reported() {
+        let (people, timelines) = mock_people();
+        let mock = crate::mock::MockChurch::start(people, timelines, false).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = mock.env(dir.path());
+        env.church_password = "wrong".to_string();
+        let client = ChurchClient::with_urls(env, mock.urls()).await.unwrap();
+
+        let err = client.login().await.unwrap_err();
+        assert!(err.to_string().contains("Password is incorrect"));
+        assert!(matches!(err, Error::AuthenticationFailed(_)));
+        assert_eq!(err.exit_code(), 2);
+    }
+
+    #[tokio::test]
+    async fn expired_saved_token_logs_in_first() {
+        let (people, timelines) = mock_people();
+        let mock = crate::mock::MockChurch::start(people, timelines, false).await;
+        let dir = tempfile::tempdir().unwrap();
+        let stale = crate::mock::bearer_token(chrono::Utc::now() - Duration::minutes(5));
+        std::fs::write(dir.path().join("bearer.token"), stale).unwrap();
+
+        let client = ChurchClient::with_urls(mock.env(dir.path()), mock.urls()).await.unwrap();
+        assert_eq!(client.get_people_list().await.unwrap().len(), 1);
+
+        let paths = mock.requested_paths().await;
+        let login = paths.iter().position(|p| p == "/services/auth").unwrap();
+        let list = paths.iter().position(|p| p.starts_with("/services/people")).unwrap();
+        assert!(login < list, "should log in before asking for the list: {paths:?}");
+        assert_eq!(paths.iter().filter(|p| p.starts_with("/services/people")).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_login() {
+        let (people, timelines) = mock_people();
+        let mock = crate::mock::MockChurch::start(people, timelines, false).await;
+        let dir = tempfile::tempdir().unwrap();
+        let client = Arc::new(ChurchClient::with_urls(mock.env(dir.path()), mock.urls()).await.unwrap());
+
+        let tasks: Vec<_> = (0..5)
+            .map(|_| {
+                let client = Arc::clone(&client);
+                tokio::spawn(async move { client.get_people_list().await.map(|list| list.len()) })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap(), 1);
+        }
+
+        let paths = mock.requested_paths().await;
+        assert_eq!(paths.iter().filter(|p| *p == "/services/auth").count(), 1);
+        assert_eq!(paths.iter().filter(|p| p.starts_with("/services/people")).count(), 5);
+    }
+
+    #[tokio::test]
+    async fn timeline_is_used_when_it_cant_be_cached() {
+        let (people, timelines) = mock_people();
+        let mock = crate::mock::MockChurch::start(people, timelines, false).await;
+        let dir = tempfile::tempdir().unwrap();
+        // A file where the cache directory should be
+        std::fs::write(dir.path().join(TIMELINES_DIR), "").unwrap();
+        let client = ChurchClient::with_urls(mock.env(dir.path()), mock.urls()).await.unwrap();
+
+        let list = client.get_people_list().await.unwrap();
+        assert!(!client.get_person_timeline(&list[0]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn timelines_are_cached_until_refreshed() {
+        let (people, timelines) = mock_people();
+        let mock = crate::mock::MockChurch::start(people, timelines, false).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = ChurchClient::with_urls(mock.env(dir.path()), mock.urls()).await.unwrap();
+
+        let list = client.get_people_list().await.unwrap();
+        let first = client.get_person_timeline(&list[0]).await.unwrap();
+        let cached = client.get_person_timeline(&list[0]).await.unwrap();
+        assert_eq!(crate::scoring::contact_metrics(&cached, &client.settings.scoring).first_attempt_minutes, Some(180));
+        assert!(dir.path().join("timelines/guid-1.json").exists());
+        assert_eq!(timeline_requests(&mock.requested_paths().await), 1);
+
+        // Cached events parse the same as fresh ones
+        client.refresh.insert("guid-1".to_string());
+        assert_eq!(client.get_person_timeline(&list[0]).await.unwrap(), first);
+        assert_eq!(timeline_requests(&mock.requested_paths().await), 2);
+
+        client.refresh.clear();
+        client.settings.timeline_ttl_minutes = 0;
+        client.get_person_timeline(&list[0]).await.unwrap();
+        assert_eq!(timeline_requests(&mock.requested_paths().await), 3);
+    }
+
+    fn timeline_requests(paths: &[String]) -> usize {
+        paths.iter().filter(|p| p.starts_with("/services/progress/timeline")).count()
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        use wiremock::{matchers::path_regex, Mock, ResponseTemplate};
+        let (people, timelines) = mock_people();
+        let mock = crate::mock::MockChurch::start(people, timelines, false).await;
+        Mock::given(path_regex("^/services/progress/timeline/"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .mount(&mock.server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = ChurchClient::with_urls(mock.env(dir.path()), mock.urls()).await.unwrap();
+        client.settings.retry.base_delay_ms = 1;
+
+        let list = client.get_people_list().await.unwrap();
+        assert_eq!(client.get_person_timeline(&list[0]).await.unwrap().len(), 2);
+        assert_eq!(timeline_requests(&mock.requested_paths().await), 3);
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_fail_fast() {
+        let (people, _) = mock_people();
+        let mock = crate::mock::MockChurch::start(people, Vec::new(), false).await;
+        let dir = tempfile::tempdir().unwrap();
+        let client = ChurchClient::with_urls(mock.env(dir.path()), mock.urls()).await.unwrap();
+
+        let list = client.get_people_list().await.unwrap();
+        let err = client.get_person_timeline(&list[0]).await.unwrap_err();
+        assert!(matches!(err, Error::Server { status, .. } if status == reqwest::StatusCode::NOT_FOUND));
+        assert_eq!(timeline_requests(&mock.requested_paths().await), 1);
+    }
+
+    #[tokio::test]
+    async fn refused_session_logs_in_once() {
+        use wiremock::{matchers::path_regex, Mock, ResponseTemplate};
+        let (people, timelines) = mock_people();
+        let mock = crate::mock::MockChurch::start(people, timelines, false).await;
+        Mock::given(path_regex("^/services/progress/timeline/"))
+            .respond_with(ResponseTemplate::new(401))
+            .with_priority(1)
+            .mount(&mock.server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let client = ChurchClient::with_urls(mock.env(dir.path()), mock.urls()).await.unwrap();
+
+        let list = client.get_people_list().await.unwrap();
+        let err = client.get_person_timeline(&list[0]).await.unwrap_err();
+        assert_eq!(err.exit_code(), 2);
+        let paths = mock.requested_paths().await;
+        assert_eq!(timeline_requests(&paths), 2);
+        assert_eq!(paths.iter().filter(|p| *p == "/services/auth").count(), 2);
+    }
 }
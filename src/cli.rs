@@ -0,0 +1,135 @@
+// Command line arguments
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::{persons::TimelineItemType, scoring::ScoringRules};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Grabs referral data, encrypts it, and sends it to an endpoint")]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// Runcode from a previous run. This is what Task Scheduler passes in.
+    pub runcode: Option<String>,
+
+    /// Download this person's timeline again even if it's cached. Can be repeated.
+    #[arg(long, value_name = "GUID")]
+    pub refresh: Vec<String>,
+
+    #[command(flatten)]
+    pub scoring: ScoringArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Overrides for the `scoring` rules in settings.json, for this run only
+#[derive(Debug, Args)]
+pub struct ScoringArgs {
+    /// The mission's time zone, e.g. America/Denver
+    #[arg(long, value_name = "IANA_NAME")]
+    pub time_zone: Option<chrono_tz::Tz>,
+    /// Days scored, starting on the referral day
+    #[arg(long, value_name = "DAYS")]
+    pub window_days: Option<u32>,
+    /// Only score people assigned within this many days
+    #[arg(long, value_name = "DAYS")]
+    pub lookback_days: Option<i64>,
+    /// Score up to this many days before today. 1 scores through yesterday.
+    #[arg(long, value_name = "DAYS")]
+    pub cutoff_days: Option<i64>,
+    /// Timeline event that counts as a contact, e.g. CONTACT or TEACHING. Can be repeated.
+    #[arg(long, value_name = "TYPE", value_parser = parse_item_type)]
+    pub contact_type: Vec<TimelineItemType>,
+    /// Whether a day with only unsuccessful attempts counts as a contacted day
+    #[arg(long, value_name = "BOOL")]
+    pub count_failed_attempts: Option<bool>,
+}
+
+impl ScoringArgs {
+    pub fn apply(self, rules: &mut ScoringRules) {
+        if let Some(time_zone) = self.time_zone {
+            rules.time_zone = time_zone;
+        }
+        if let Some(days) = self.window_days {
+            rules.window_days = days;
+        }
+        if let Some(days) = self.lookback_days {
+            rules.lookback_days = days;
+        }
+        if let Some(days) = self.cutoff_days {
+            rules.cutoff_days = days;
+        }
+        if !self.contact_type.is_empty() {
+            rules.contact_types = self.contact_type;
+        }
+        if let Some(count) = self.count_failed_attempts {
+            rules.count_failed_attempts = count;
+        }
+    }
+}
+
+/// Timeline event types are named the way referral manager names them
+fn parse_item_type(name: &str) -> Result<TimelineItemType, String> {
+    serde_json::from_value(serde_json::Value::String(name.to_uppercase()))
+        .map_err(|_| format!("{name} is not a timeline event type"))
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Verify and decrypt a v2 envelope that an endpoint received
+    Open {
+        /// File holding the posted JSON, or `-` for stdin
+        path: String,
+    },
+    /// Manage the encrypted credential vault in the working path
+    Vault {
+        #[command(subcommand)]
+        action: VaultAction,
+    },
+    /// Show what changed between two people list snapshots
+    Diff {
+        /// Older snapshot, as shown by `cache list`. Defaults to the one before `to`.
+        from: Option<u64>,
+        /// Newer snapshot. Defaults to the newest.
+        to: Option<u64>,
+        /// Print the changes as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show how areas did in past runs
+    History {
+        /// Only this area
+        #[arg(long)]
+        area: Option<String>,
+        /// How many days back to go
+        #[arg(long, default_value_t = 30)]
+        days: i64,
+    },
+    /// Manage the people list snapshots in the working path
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum VaultAction {
+    /// Store a value, e.g. `vault set CHURCH_PASSWORD`. The value is prompted for.
+    Set { key: String },
+    /// Delete a stored value
+    Remove { key: String },
+    /// Change the vault passphrase
+    Rotate,
+    /// Show which keys are stored
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheAction {
+    /// Show the saved snapshots, newest first
+    List,
+    /// Delete the snapshots the retention settings don't keep
+    Prune,
+    /// Delete every snapshot and cached timeline
+    Clear,
+}
@@ -0,0 +1,235 @@
+// Changes between two people list snapshots
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    cache::SnapshotCache,
+    persons::{Person, PersonStatus, ReferralStatus},
+    settings::Settings,
+};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SnapshotDiff {
+    pub new_referrals: Vec<NewReferral>,
+    pub reassigned: Vec<Reassignment>,
+    pub person_status: Vec<Transition<PersonStatus>>,
+    pub referral_status: Vec<Transition<ReferralStatus>>,
+}
+
+/// Someone who wasn't in the older snapshot, or was referred again since
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct NewReferral {
+    pub guid: String,
+    pub name: String,
+    pub area: Option<String>,
+    pub zone: Option<String>,
+    pub assigned_date: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Reassignment {
+    pub guid: String,
+    pub name: String,
+    pub from_area: Option<String>,
+    pub to_area: Option<String>,
+    pub from_zone: Option<String>,
+    pub to_zone: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Transition<T> {
+    pub guid: String,
+    pub name: String,
+    pub from: T,
+    pub to: T,
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.new_referrals.is_empty()
+            && self.reassigned.is_empty()
+            && self.person_status.is_empty()
+            && self.referral_status.is_empty()
+    }
+}
+
+/// Compares `older` to `newer`, matching people by GUID. People missing from
+/// `newer` aren't reported, since the list includes dropped people anyway.
+pub fn diff(older: &[Person], newer: &[Person]) -> SnapshotDiff {
+    let older: HashMap<&str, &Person> = older.iter().map(|p| (p.guid.as_str(), p)).collect();
+    let mut diff = SnapshotDiff::default();
+
+    for person in newer {
+        let Some(before) = older.get(person.guid.as_str()) else {
+            diff.new_referrals.push(NewReferral::from(person));
+            continue;
+        };
+        if person.assigned_date > before.assigned_date {
+            diff.new_referrals.push(NewReferral::from(person));
+        }
+        if person.area_name != before.area_name || person.zone_name != before.zone_name {
+            diff.reassigned.push(Reassignment {
+                guid: person.guid.clone(),
+                name: person.first_name.clone(),
+                from_area: before.area_name.clone(),
+                to_area: person.area_name.clone(),
+                from_zone: before.zone_name.clone(),
+                to_zone: person.zone_name.clone(),
+            });
+        }
+        if person.person_status != before.person_status {
+            diff.person_status.push(Transition::new(person, before.person_status.clone(), person.person_status.clone()));
+        }
+        if person.referral_status != before.referral_status {
+            diff.referral_status.push(Transition::new(
+                person,
+                before.referral_status.clone(),
+                person.referral_status.clone(),
+            ));
+        }
+    }
+    diff
+}
+
+impl From<&Person> for NewReferral {
+    fn from(person: &Person) -> Self {
+        Self {
+            guid: person.guid.clone(),
+            name: person.first_name.clone(),
+            area: person.area_name.clone(),
+            zone: person.zone_name.clone(),
+            assigned_date: person.assigned_date,
+        }
+    }
+}
+
+impl<T> Transition<T> {
+    fn new(person: &Person, from: T, to: T) -> Self {
+        Self {
+            guid: person.guid.clone(),
+            name: person.first_name.clone(),
+            from,
+            to,
+        }
+    }
+}
+
+/// Diffs the two newest snapshots in the working path, if there are two
+pub fn latest(cache: &SnapshotCache) -> crate::error::Result<Option<SnapshotDiff>> {
+    let snapshots = cache.list()?;
+    let [newer, older, ..] = snapshots.as_slice() else {
+        return Ok(None);
+    };
+    Ok(Some(diff(&cache.load(older)?, &cache.load(newer)?)))
+}
+
+/// Prints the changes between two snapshots, by default the two newest
+pub fn diff_command(from: Option<u64>, to: Option<u64>, json: bool) -> anyhow::Result<()> {
+    let working_path = crate::env::default_working_path();
+    let cache = SnapshotCache::new(&working_path, Settings::load(&working_path)?.cache);
+    let snapshots = cache.list()?;
+    let find = |taken_at: u64| {
+        snapshots
+            .iter()
+            .find(|s| s.taken_at == taken_at)
+            .ok_or_else(|| anyhow::anyhow!("No snapshot taken at {taken_at}, see `cache list`"))
+    };
+    let newer = match to {
+        Some(to) => find(to)?,
+        None => snapshots.first().ok_or_else(|| anyhow::anyhow!("No people list snapshots saved"))?,
+    };
+    let older = match from {
+        Some(from) => find(from)?,
+        None => snapshots
+            .iter()
+            .find(|s| s.taken_at < newer.taken_at)
+            .ok_or_else(|| anyhow::anyhow!("No older snapshot to compare {} with", newer.taken_at))?,
+    };
+
+    let diff = diff(&cache.load(older)?, &cache.load(newer)?);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+        return Ok(());
+    }
+    println!("Changes from {} to {}", older.taken_at, newer.taken_at);
+    if diff.is_empty() {
+        println!("Nothing changed");
+    }
+    for p in &diff.new_referrals {
+        println!("New referral: {} ({}) in {}", p.name, p.guid, p.area.as_deref().unwrap_or("no area"));
+    }
+    for p in &diff.reassigned {
+        println!(
+            "Reassigned: {} ({}) from {} / {} to {} / {}",
+            p.name,
+            p.guid,
+            p.from_area.as_deref().unwrap_or("no area"),
+            p.from_zone.as_deref().unwrap_or("no zone"),
+            p.to_area.as_deref().unwrap_or("no area"),
+            p.to_zone.as_deref().unwrap_or("no zone"),
+        );
+    }
+    for p in &diff.person_status {
+        println!("Person status: {} ({}) {:?} -> {:?}", p.name, p.guid, p.from, p.to);
+    }
+    for p in &diff.referral_status {
+        println!("Referral status: {} ({}) {:?} -> {:?}", p.name, p.guid, p.from, p.to);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn people(values: Vec<serde_json::Value>) -> Vec<Person> {
+        Person::parse_lossy(serde_json::json!({ "persons": values }))
+    }
+
+    #[test]
+    fn reports_each_kind_of_change() {
+        use crate::mock::person;
+        let assigned = Utc::now() - Duration::days(3);
+        let older = people(vec![
+            person("moved", "Alex", "Riverside", assigned),
+            person("same", "Sam", "Hillcrest", assigned),
+            person("progressed", "Kim", "Hillcrest", assigned),
+            person("rereferred", "Lee", "Hillcrest", assigned),
+        ]);
+        let mut progressed = person("progressed", "Kim", "Hillcrest", assigned);
+        progressed["personStatusId"] = 2.into();
+        progressed["referralStatusId"] = 30.into();
+        let newer = people(vec![
+            person("moved", "Alex", "Lakeside", assigned),
+            person("same", "Sam", "Hillcrest", assigned),
+            progressed,
+            person("rereferred", "Lee", "Hillcrest", assigned + Duration::days(2)),
+            person("brand-new", "Jo", "Riverside", assigned),
+        ]);
+
+        let diff = diff(&older, &newer);
+        let guids = |names: Vec<&str>| names.into_iter().map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            guids(diff.new_referrals.iter().map(|p| p.guid.as_str()).collect()),
+            guids(vec!["rereferred", "brand-new"])
+        );
+        assert_eq!(diff.reassigned.len(), 1);
+        assert_eq!(diff.reassigned[0].from_area.as_deref(), Some("Riverside"));
+        assert_eq!(diff.reassigned[0].to_area.as_deref(), Some("Lakeside"));
+        assert_eq!(diff.person_status.len(), 1);
+        assert_eq!(diff.person_status[0].to, PersonStatus::Green);
+        assert_eq!(diff.referral_status.len(), 1);
+        assert_eq!(diff.referral_status[0].from, ReferralStatus::NotAttempted);
+        assert_eq!(diff.referral_status[0].to, ReferralStatus::Successful);
+    }
+
+    #[test]
+    fn identical_snapshots_have_no_changes() {
+        let list = people(vec![crate::mock::person("a", "Alex", "Riverside", Utc::now())]);
+        assert!(diff(&list, &list).is_empty());
+    }
+}
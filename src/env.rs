@@ -3,21 +3,31 @@
 use std::{
     collections::HashMap,
     io::{ BufRead, Write },
-    path::PathBuf,
+    path::{ Path, PathBuf },
     str::FromStr,
     fs::OpenOptions,
 };
 
-use crate::persons;
+use crate::{ cli::VaultAction, persons, send::EnvelopeFormat, vault::Vault };
 use dialoguer::{ theme::ColorfulTheme, Input, Password, Select };
-use serde_json;
 //use serde::Deserialize;
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
 pub struct Env {
     pub church_username: String,
     pub church_password: String,
     pub timeline_send_url: String,
+    /// Key shared with the endpoint (the "config" tab's B2 cell for the Apps Script handler).
+    /// Runcodes made before this existed deserialize with an empty key.
+    #[serde(default)]
+    pub crypt_key: String,
+    /// Runcodes made before v2 envelopes existed keep sending the legacy format
+    #[serde(default)]
+    pub envelope_format: EnvelopeFormat,
+    /// Base32 seed for an authenticator app, so unattended runs can answer TOTP challenges.
+    /// Only read from TOTP_SEED, never prompted for.
+    #[serde(default)]
+    pub totp_seed: Option<String>,
     pub working_path: String,
 }
 
@@ -30,72 +40,240 @@ pub struct Env {
 /// reading and writing env vars thread safe.
 pub fn check_vars() -> Env {
     dotenvy::dotenv().ok();
+    let working_path = default_working_path();
+    let mut vars = Vars::new(working_path.clone());
 
     Env {
-        church_username: std::env::var("CHURCH_USERNAME").unwrap_or_else(|_| {
+        church_username: vars.get("CHURCH_USERNAME").unwrap_or_else(|| {
             let password: String = Input::with_theme(&ColorfulTheme::default())
                 .with_prompt("Enter your churchofjesuschrist.org username")
                 .interact()
                 .unwrap();
 
-            save_var("CHURCH_USERNAME", &password);
+            vars.save("CHURCH_USERNAME", &password);
             password
         }),
-        church_password: std::env::var("CHURCH_PASSWORD").unwrap_or_else(|_| {
+        church_password: vars.get("CHURCH_PASSWORD").unwrap_or_else(|| {
             let password: String = Password::with_theme(&ColorfulTheme::default())
                 .with_prompt("Enter your churchofjesuschrist.org password")
                 .with_confirmation("Repeat password", "Error: the passwords don't match.")
                 .interact()
                 .unwrap();
 
-            save_var("CHURCH_PASSWORD", &password);
+            vars.save("CHURCH_PASSWORD", &password);
             password
         }),
-        timeline_send_url: std::env::var("TIMELINE_SEND_URL").unwrap_or_else(|_| {
+        timeline_send_url: vars.get("TIMELINE_SEND_URL").unwrap_or_else(|| {
             let password: String = Input::with_theme(&ColorfulTheme::default())
                 .with_prompt("Enter the url to POST timeline data to")
                 .default("/".to_string())
                 .interact()
                 .unwrap();
 
-            save_var("TIMELINE_SEND_URL", &password);
+            vars.save("TIMELINE_SEND_URL", &password);
             password
         }),
-        working_path: {
-            let here = std::env::current_dir().unwrap().join("rm_working_path");
-            if std::fs::create_dir_all(&here).is_err() {
-                log::error!("Creating directory {here:?} failed!");
+        crypt_key: vars.get("CRYPT_KEY").unwrap_or_else(|| {
+            let password: String = Password::with_theme(&ColorfulTheme::default())
+                .with_prompt("Enter the CRYPT key your endpoint decrypts with")
+                .interact()
+                .unwrap();
+
+            vars.save("CRYPT_KEY", &password);
+            password
+        }),
+        envelope_format: vars
+            .get("ENVELOPE_FORMAT")
+            .and_then(|format| format.parse().ok())
+            .unwrap_or_else(|| {
+                let selections = &[
+                    "legacy (the AppsScriptPostHandler.js in this repo)",
+                    "v2 (authenticated, needs a v2 aware endpoint)",
+                ];
+                let selection = Select::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Which envelope format does your endpoint accept?")
+                    .default(0)
+                    .items(selections)
+                    .interact()
+                    .unwrap();
+                let format = if selection == 0 { EnvelopeFormat::Legacy } else { EnvelopeFormat::V2 };
+
+                vars.save(
+                    "ENVELOPE_FORMAT",
+                    if format == EnvelopeFormat::Legacy { "legacy" } else { "v2" }
+                );
+                format
+            }),
+        totp_seed: vars.get("TOTP_SEED").filter(|seed| !seed.trim().is_empty()),
+        working_path: working_path.to_string_lossy().to_string(),
+    }
+}
+
+/// `rm_working_path` in the current directory, created if needed
+pub fn default_working_path() -> PathBuf {
+    let here = std::env::current_dir().unwrap().join("rm_working_path");
+    if std::fs::create_dir_all(&here).is_err() {
+        log::error!("Creating directory {here:?} failed!");
+    }
+    here
+}
+
+/// Looks values up in the process environment (which .env is loaded into) first,
+/// then in the encrypted vault. The vault is only unlocked once something needs it.
+struct Vars {
+    working_path: PathBuf,
+    vault: Option<Vault>,
+    vault_unavailable: bool,
+}
+
+impl Vars {
+    fn new(working_path: PathBuf) -> Self {
+        Self {
+            working_path,
+            vault: None,
+            vault_unavailable: false,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<String> {
+        if let Ok(val) = std::env::var(key) {
+            return Some(val);
+        }
+        if !Vault::exists(&self.working_path) {
+            return None;
+        }
+        self.vault()?.get(key).map(str::to_string)
+    }
+
+    fn vault(&mut self) -> Option<&mut Vault> {
+        if self.vault.is_none() && !self.vault_unavailable {
+            match unlock_vault(&self.working_path) {
+                Ok(vault) => {
+                    self.vault = Some(vault);
+                }
+                Err(e) => {
+                    log::error!("Unable to unlock the credential vault: {e}");
+                    self.vault_unavailable = true;
+                }
+            }
+        }
+        self.vault.as_mut()
+    }
+
+    fn save(&mut self, key: &str, val: &str) {
+        // Save the variable to the environment
+        std::env::set_var(key, val);
+
+        // Ask if and where the user wants to keep the value
+        let selections = &[
+            "Yes, in the encrypted credential vault",
+            "Yes, in plaintext in the .env file",
+            "No",
+        ];
+        let selection = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("Save this for future startups? If unsure, use the vault.")
+            .default(0)
+            .items(selections)
+            .interact()
+            .unwrap();
+
+        match selection {
+            0 => {
+                let saved = self.vault().map(|vault| {
+                    vault.set(key, val);
+                    vault.save()
+                });
+                match saved {
+                    Some(Ok(())) => {}
+                    Some(Err(e)) => log::error!("Saving {key} to the vault failed: {e}"),
+                    None => log::error!("{key} was not saved, the vault is locked"),
+                }
             }
-            let here = here.to_string_lossy();
-            here.to_string()
-        },
+            1 => {
+                // Write to the .env file
+                let mut file = OpenOptions::new().append(true).create(true).open(".env").unwrap();
+
+                file.write_all(format!("{key}={val}\n").as_bytes()).unwrap();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Opens the vault with VAULT_PASSPHRASE, or by prompting for the passphrase.
+/// A vault that doesn't exist yet gets a new passphrase.
+pub fn unlock_vault(working_path: &Path) -> anyhow::Result<Vault> {
+    if let Ok(passphrase) = std::env::var("VAULT_PASSPHRASE") {
+        return Vault::open(working_path, &passphrase);
+    }
+
+    if !Vault::exists(working_path) {
+        let passphrase = Password::with_theme(&ColorfulTheme::default())
+            .with_prompt("Choose a passphrase for the credential vault")
+            .with_confirmation("Repeat passphrase", "Error: the passphrases don't match.")
+            .interact()?;
+        return Vault::open(working_path, &passphrase);
+    }
+
+    let mut tries = 0;
+    loop {
+        tries += 1;
+        let passphrase = Password::with_theme(&ColorfulTheme::default())
+            .with_prompt("Credential vault passphrase")
+            .interact()?;
+        match Vault::open(working_path, &passphrase) {
+            Ok(vault) => return Ok(vault),
+            Err(e) if tries >= 3 => return Err(e),
+            Err(e) => println!("{e}"),
+        }
     }
 }
 
-fn save_var(key: &str, val: &str) {
-    // Use sanitize_input to make sure we are saving a sanitized value
-
-    // Save the variable to the environment
-    std::env::set_var(key, val);
-
-    // Ask if the user wants to save the value to the .env file
-    let selections = &["Yes", "No"];
-    let selection = Select::with_theme(&ColorfulTheme::default())
-        .with_prompt(
-            "Save this for future startups? This value will be saved in your .env file. If unsure, select yes."
-        )
-        .default(0)
-        .items(selections)
-        .interact()
-        .unwrap();
-
-    if selection == 0 {
-        // Write to the .env file
-        let mut file = OpenOptions::new().append(true).create(true).open(".env").unwrap();
-
-        // Write the sanitized variable to the .env file
-        file.write_all(format!("{key}={val}\n").as_bytes()).unwrap();
+/// Handles `vault set`, `vault remove`, `vault rotate` and `vault list`
+pub fn vault_command(action: VaultAction) -> anyhow::Result<()> {
+    dotenvy::dotenv().ok();
+    let working_path = default_working_path();
+    let mut vault = unlock_vault(&working_path)?;
+
+    match action {
+        VaultAction::Set { key } => {
+            let key = key.to_uppercase();
+            let val = Password::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!("Value for {key}"))
+                .allow_empty_password(true)
+                .interact()?;
+            vault.set(&key, &val);
+            vault.save()?;
+            println!("Saved {key} to the vault");
+        }
+        VaultAction::Remove { key } => {
+            let key = key.to_uppercase();
+            if vault.remove(&key) {
+                vault.save()?;
+                println!("Removed {key} from the vault");
+            } else {
+                println!("{key} is not in the vault");
+            }
+            if std::env::var(&key).is_ok() {
+                println!("{key} is still set in the environment or the .env file");
+            }
+        }
+        VaultAction::Rotate => {
+            let passphrase = Password::with_theme(&ColorfulTheme::default())
+                .with_prompt("New vault passphrase")
+                .with_confirmation("Repeat passphrase", "Error: the passphrases don't match.")
+                .interact()?;
+            vault.rotate(&passphrase);
+            vault.save()?;
+            println!("Vault passphrase changed");
+        }
+        VaultAction::List => {
+            for key in vault.keys() {
+                println!("{key}");
+            }
+        }
     }
+    Ok(())
 }
 
 impl Env {
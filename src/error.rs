@@ -0,0 +1,126 @@
+// Errors from talking to church servers and the endpoint, with the exit code
+// each one ends the process with so schedulers can tell them apart
+
+use reqwest::StatusCode;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Bad username or password, a refused code, or a session the server won't accept
+    #[error("Authentication failed: {0}")]
+    AuthenticationFailed(String),
+
+    /// A second factor is needed and there's no way to answer it
+    #[error("Multi-factor authentication required: {0}")]
+    MfaRequired(String),
+
+    #[error("Rate limited by {url}")]
+    RateLimited {
+        url: String,
+        /// Seconds from the Retry-After header, if there was one
+        retry_after: Option<u64>,
+    },
+
+    #[error("{url} responded with {status}")]
+    Server { status: StatusCode, url: String },
+
+    /// A response didn't look like it used to
+    #[error("Unexpected response from church servers: {0}")]
+    SchemaDrift(String),
+
+    #[error("Endpoint rejected the data with {status}: {body}")]
+    EndpointRejected { status: StatusCode, body: String },
+
+    /// Reading or writing the working path
+    #[error("Cache I/O failed: {0}")]
+    CacheIo(#[from] std::io::Error),
+
+    #[error("Network error: {0}")]
+    Network(reqwest::Error),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl Error {
+    /// Process exit code for this error. 0 is success and 1 is anything unexpected.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Other(_) => 1,
+            Error::AuthenticationFailed(_) => 2,
+            Error::MfaRequired(_) => 3,
+            Error::RateLimited { .. } => 4,
+            Error::Server { .. } => 5,
+            Error::Network(_) => 6,
+            Error::SchemaDrift(_) => 7,
+            Error::EndpointRejected { .. } => 8,
+            Error::CacheIo(_) => 9,
+        }
+    }
+
+    /// Maps an unsuccessful HTTP status to an error
+    pub fn from_status(status: StatusCode, url: &str, retry_after: Option<u64>) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Error::AuthenticationFailed(format!("{url} responded with {status}"))
+            }
+            StatusCode::TOO_MANY_REQUESTS => Error::RateLimited {
+                url: url.to_string(),
+                retry_after,
+            },
+            _ => Error::Server {
+                status,
+                url: url.to_string(),
+            },
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            Error::SchemaDrift(e.to_string())
+        } else {
+            Error::Network(e)
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::SchemaDrift(e.to_string())
+    }
+}
+
+/// Turns a non-2xx response into an error
+pub fn check_status(res: reqwest::Response) -> Result<reqwest::Response> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    let retry_after = res
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.trim().parse().ok());
+    Err(Error::from_status(status, res.url().as_str(), retry_after))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_map_to_distinct_exit_codes() {
+        let auth = Error::from_status(StatusCode::UNAUTHORIZED, "u", None);
+        let limited = Error::from_status(StatusCode::TOO_MANY_REQUESTS, "u", Some(5));
+        let server = Error::from_status(StatusCode::BAD_GATEWAY, "u", None);
+        assert!(matches!(auth, Error::AuthenticationFailed(_)));
+        assert!(matches!(limited, Error::RateLimited { retry_after: Some(5), .. }));
+        assert!(matches!(server, Error::Server { .. }));
+
+        let codes = [auth.exit_code(), limited.exit_code(), server.exit_code()];
+        assert_eq!(codes, [2, 4, 5]);
+    }
+}
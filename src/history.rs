@@ -0,0 +1,412 @@
+// Run history kept in a SQLite database in the working path, so results can be
+// compared across runs
+
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+
+use crate::{
+    persons::{Person, ReferralPerson, TimelineEvent},
+    scoring::Outcome,
+};
+
+pub const HISTORY_FILE: &str = "history.sqlite3";
+
+/// Schema changes, applied in order. `PRAGMA user_version` holds how many have run.
+/// Append to this list, never edit an entry that has shipped.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE runs (
+        id INTEGER PRIMARY KEY,
+        started_at TEXT NOT NULL,
+        finished_at TEXT NOT NULL
+    );
+    CREATE TABLE people (
+        run_id INTEGER NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+        guid TEXT NOT NULL,
+        first_name TEXT NOT NULL,
+        referral_status INTEGER NOT NULL,
+        person_status INTEGER NOT NULL,
+        mission_id INTEGER NOT NULL,
+        zone_id INTEGER,
+        zone_name TEXT,
+        district_id INTEGER,
+        area_name TEXT,
+        assigned_date TEXT NOT NULL,
+        PRIMARY KEY (run_id, guid)
+    );
+    CREATE TABLE timeline_events (
+        run_id INTEGER NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+        guid TEXT NOT NULL,
+        item_type TEXT NOT NULL,
+        item_date TEXT NOT NULL,
+        status INTEGER
+    );
+    CREATE INDEX timeline_events_guid ON timeline_events (guid, item_date);
+    CREATE TABLE results (
+        run_id INTEGER NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+        guid TEXT NOT NULL,
+        name TEXT NOT NULL,
+        area TEXT NOT NULL,
+        contact_time INTEGER NOT NULL,
+        score TEXT NOT NULL,
+        referral_status TEXT NOT NULL,
+        PRIMARY KEY (run_id, guid)
+    );
+    CREATE INDEX results_area ON results (area);",
+    "ALTER TABLE results ADD COLUMN success_time INTEGER;
+    ALTER TABLE results ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;",
+    // One result per referral episode, so the referral date joins the key
+    "CREATE TABLE results_by_episode (
+        run_id INTEGER NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+        guid TEXT NOT NULL,
+        referral_date TEXT NOT NULL DEFAULT '',
+        name TEXT NOT NULL,
+        area TEXT NOT NULL,
+        contact_time INTEGER NOT NULL,
+        score TEXT NOT NULL,
+        referral_status TEXT NOT NULL,
+        success_time INTEGER,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (run_id, guid, referral_date)
+    );
+    INSERT INTO results_by_episode (run_id, guid, name, area, contact_time, score, referral_status,
+        success_time, attempts)
+        SELECT run_id, guid, name, area, contact_time, score, referral_status, success_time, attempts FROM results;
+    DROP TABLE results;
+    ALTER TABLE results_by_episode RENAME TO results;
+    CREATE INDEX results_area ON results (area);",
+    // People not contacted yet are kept, with no contact time
+    "CREATE TABLE results_with_uncontacted (
+        run_id INTEGER NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+        guid TEXT NOT NULL,
+        referral_date TEXT NOT NULL DEFAULT '',
+        name TEXT NOT NULL,
+        area TEXT NOT NULL,
+        contact_time INTEGER,
+        score TEXT NOT NULL,
+        referral_status TEXT NOT NULL,
+        success_time INTEGER,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        elapsed_time INTEGER,
+        PRIMARY KEY (run_id, guid, referral_date)
+    );
+    INSERT INTO results_with_uncontacted (run_id, guid, referral_date, name, area, contact_time, score,
+        referral_status, success_time, attempts)
+        SELECT run_id, guid, referral_date, name, area, contact_time, score, referral_status, success_time, attempts
+        FROM results;
+    DROP TABLE results;
+    ALTER TABLE results_with_uncontacted RENAME TO results;
+    CREATE INDEX results_area ON results (area);",
+];
+
+/// Everything one run fetched and worked out
+pub struct RunRecord<'a> {
+    pub started_at: DateTime<Utc>,
+    pub people: &'a [Person],
+    pub timelines: &'a [(String, Vec<TimelineEvent>)],
+    pub results: &'a [ReferralPerson],
+}
+
+/// How one area's referrals stood after one run
+#[derive(Clone, Debug, PartialEq)]
+pub struct AreaRun {
+    pub started_at: DateTime<Utc>,
+    pub area: String,
+    pub referrals: usize,
+    pub successful: usize,
+    /// Over the referrals someone has contacted, None when no one has
+    pub mean_contact_minutes: Option<f64>,
+}
+
+/// A referral episode's latest result in a span of runs
+#[derive(Clone, Debug, PartialEq)]
+pub struct LatestResult {
+    pub guid: String,
+    pub area: String,
+    pub zone: Option<String>,
+    /// None when no one had contacted them yet
+    pub contact_time: Option<usize>,
+    pub successful: bool,
+}
+
+pub struct History {
+    conn: Connection,
+}
+
+impl History {
+    pub fn open(working_path: &Path) -> anyhow::Result<Self> {
+        Self::with_connection(Connection::open(working_path.join(HISTORY_FILE))?)
+    }
+
+    fn with_connection(conn: Connection) -> anyhow::Result<Self> {
+        conn.pragma_update(None, "foreign_keys", true)?;
+        let mut history = Self { conn };
+        history.migrate()?;
+        Ok(history)
+    }
+
+    fn migrate(&mut self) -> anyhow::Result<()> {
+        let version: usize = self.conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            return Err(anyhow::anyhow!(
+                "{HISTORY_FILE} was made by a newer version of this program (schema {version})"
+            ));
+        }
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = self.conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Saves a run in one transaction and returns its id
+    pub fn record_run(&mut self, run: &RunRecord) -> anyhow::Result<i64> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO runs (started_at, finished_at) VALUES (?1, ?2)",
+            params![run.started_at, Utc::now()],
+        )?;
+        let run_id = tx.last_insert_rowid();
+        {
+            let mut insert = tx.prepare(
+                "INSERT OR REPLACE INTO people (run_id, guid, first_name, referral_status, person_status,
+                 mission_id, zone_id, zone_name, district_id, area_name, assigned_date)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            )?;
+            for p in run.people {
+                insert.execute(params![
+                    run_id,
+                    p.guid,
+                    p.first_name,
+                    p.referral_status.clone() as u8,
+                    p.person_status.clone() as u8,
+                    p.mission_id as i64,
+                    p.zone_id.map(|id| id as i64),
+                    p.zone_name,
+                    p.district_id.map(|id| id as i64),
+                    p.area_name,
+                    p.assigned_date.naive_utc(),
+                ])?;
+            }
+
+            let mut insert = tx.prepare(
+                "INSERT INTO timeline_events (run_id, guid, item_type, item_date, status)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for (guid, events) in run.timelines {
+                for e in events {
+                    let item_type = serde_json::to_value(&e.item_type)?;
+                    insert.execute(params![run_id, guid, item_type.as_str(), e.item_date.naive_utc(), e.status])?;
+                }
+            }
+
+            let mut insert = tx.prepare(
+                "INSERT OR REPLACE INTO results (run_id, guid, name, area, contact_time, score, referral_status,
+                 success_time, attempts, referral_date, elapsed_time)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            )?;
+            for r in run.results {
+                insert.execute(params![
+                    run_id,
+                    r.id,
+                    r.name,
+                    r.area,
+                    r.contact_time.map(|t| t as i64),
+                    r.score,
+                    r.referral_status.label(),
+                    r.success_time.map(|t| t as i64),
+                    r.attempts,
+                    r.referral_date.map(|d| d.to_string()).unwrap_or_default(),
+                    r.elapsed_time.map(|t| t as i64),
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(run_id)
+    }
+
+    /// Per run, per area results since `since`, oldest first
+    pub fn area_runs(&self, area: Option<&str>, since: DateTime<Utc>) -> anyhow::Result<Vec<AreaRun>> {
+        let mut query = self.conn.prepare(
+            "SELECT runs.started_at, results.area, COUNT(*),
+                    SUM(results.referral_status = ?3),
+                    AVG(results.contact_time)
+             FROM results JOIN runs ON runs.id = results.run_id
+             WHERE runs.started_at >= ?1 AND (?2 IS NULL OR results.area = ?2)
+             GROUP BY runs.id, results.area
+             ORDER BY runs.started_at, results.area",
+        )?;
+        let rows = query.query_map(params![since, area, Outcome::Successful.label()], |row| {
+            Ok(AreaRun {
+                started_at: row.get(0)?,
+                area: row.get(1)?,
+                referrals: row.get::<_, i64>(2)? as usize,
+                successful: row.get::<_, i64>(3)? as usize,
+                mean_contact_minutes: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Each referral episode's result from the last run after `from` and up to
+    /// `to`, so episodes scored on every run only count once
+    pub fn latest_results(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> anyhow::Result<Vec<LatestResult>> {
+        let mut query = self.conn.prepare(
+            "SELECT guid, area, zone_name, contact_time, referral_status = ?3 FROM (
+                SELECT results.guid, results.area, people.zone_name, results.contact_time, results.referral_status,
+                       ROW_NUMBER() OVER (
+                           PARTITION BY results.guid, results.referral_date ORDER BY runs.started_at DESC
+                       ) AS newest
+                FROM results JOIN runs ON runs.id = results.run_id
+                LEFT JOIN people ON people.run_id = results.run_id AND people.guid = results.guid
+                WHERE runs.started_at > ?1 AND runs.started_at <= ?2
+             )
+             WHERE newest = 1
+             ORDER BY guid",
+        )?;
+        let rows = query.query_map(params![from, to, Outcome::Successful.label()], |row| {
+            Ok(LatestResult {
+                guid: row.get(0)?,
+                area: row.get(1)?,
+                zone: row.get(2)?,
+                contact_time: row.get::<_, Option<i64>>(3)?.map(|t| t as usize),
+                successful: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
+
+/// Prints how an area, or every area, did over the last `days` days
+pub fn history_command(area: Option<String>, days: i64) -> anyhow::Result<()> {
+    let working_path = crate::env::default_working_path();
+    let history = History::open(&working_path)?;
+    let runs = history.area_runs(area.as_deref(), Utc::now() - chrono::Duration::days(days))?;
+    if runs.is_empty() {
+        println!("No runs recorded in the last {days} days");
+    }
+    for run in runs {
+        println!(
+            "{}  {:<20} {:>3} referrals  {:>3} successful  {}",
+            run.started_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"),
+            run.area,
+            run.referrals,
+            run.successful,
+            run.mean_contact_minutes
+                .map(|m| format!("{:.1} hours to contact on average", m / 60.0))
+                .unwrap_or_else(|| "no contacts".to_string()),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn referral(id: &str, area: &str, contact_time: Option<usize>, status: Outcome) -> ReferralPerson {
+        ReferralPerson::new(
+            id.to_string(),
+            id.to_string(),
+            contact_time,
+            Vec::new(),
+            area.to_string(),
+            status,
+        )
+    }
+
+    #[test]
+    fn migrations_run_once() {
+        let dir = tempfile::tempdir().unwrap();
+        History::open(dir.path()).unwrap();
+        let history = History::open(dir.path()).unwrap();
+        let version: usize = history
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        history.conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+        drop(history);
+        assert!(History::open(dir.path()).is_err());
+    }
+
+    #[test]
+    fn records_runs_and_reports_by_area() {
+        use crate::mock::{event, person};
+        let mut history = History::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        let assigned = Utc::now() - Duration::days(2);
+        let people = Person::parse_lossy(serde_json::json!({ "persons": [
+            person("a", "Alex", "Riverside", assigned),
+            person("b", "Sam", "Hillcrest", assigned),
+        ]}));
+        let timeline: Vec<TimelineEvent> =
+            TimelineEvent::parse_lossy(serde_json::json!([event("NEW_REFERRAL", assigned, None)]));
+        let timelines = vec![("a".to_string(), timeline)];
+
+        let first = vec![referral("a", "Riverside", None, Outcome::NotContacted), referral("b", "Hillcrest", Some(30), Outcome::Successful)];
+        let second = vec![
+            referral("a", "Riverside", Some(120), Outcome::Successful),
+            referral("b", "Hillcrest", Some(30), Outcome::Successful),
+            // Contacted the moment they were referred
+            referral("c", "Hillcrest", Some(0), Outcome::Successful),
+        ];
+        let started_at = Utc::now() - Duration::hours(1);
+        for results in [&first, &second] {
+            history
+                .record_run(&RunRecord {
+                    started_at,
+                    people: &people,
+                    timelines: &timelines,
+                    results,
+                })
+                .unwrap();
+        }
+
+        let riverside = history.area_runs(Some("Riverside"), Utc::now() - Duration::days(1)).unwrap();
+        assert_eq!(riverside.len(), 2);
+        assert_eq!((riverside[0].successful, riverside[0].mean_contact_minutes), (0, None));
+        assert_eq!((riverside[1].successful, riverside[1].mean_contact_minutes), (1, Some(120.0)));
+        let hillcrest = history.area_runs(Some("Hillcrest"), Utc::now() - Duration::days(1)).unwrap();
+        assert_eq!((hillcrest[1].referrals, hillcrest[1].mean_contact_minutes), (2, Some(15.0)));
+        assert_eq!(history.area_runs(None, Utc::now() - Duration::days(1)).unwrap().len(), 4);
+        assert!(history.area_runs(None, Utc::now()).unwrap().is_empty());
+
+        let events: i64 = history
+            .conn
+            .query_row("SELECT COUNT(*) FROM timeline_events WHERE guid = 'a'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(events, 2);
+    }
+
+    #[test]
+    fn keeps_a_result_per_episode() {
+        let mut history = History::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        let episodes: Vec<ReferralPerson> = ["2024-03-01", "2024-03-04"]
+            .into_iter()
+            .map(|date| {
+                let mut r = referral("a", "Riverside", Some(60), Outcome::Successful);
+                r.set_referral_date(date.parse().unwrap());
+                r
+            })
+            .collect();
+        let now = Utc::now();
+        for _ in 0..2 {
+            history
+                .record_run(&RunRecord {
+                    started_at: now - Duration::hours(1),
+                    people: &[],
+                    timelines: &[],
+                    results: &episodes,
+                })
+                .unwrap();
+        }
+        let latest = history.latest_results(now - Duration::days(1), now).unwrap();
+        assert_eq!(latest.len(), 2);
+        assert_eq!(history.area_runs(None, now - Duration::days(1)).unwrap()[0].referrals, 2);
+    }
+}
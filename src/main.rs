@@ -2,23 +2,55 @@
 
 use chrono::{Duration, Utc};
 use church::ChurchClient;
+use clap::Parser;
 //use env::Env;
 use indicatif::{ProgressBar, ProgressStyle, MultiProgress};
-use log::{info, debug, error};
+use log::{info, debug, error, warn};
 use std::sync::Arc;
 use std::time::Duration as Dur;
 use tokio::sync::{Mutex, Semaphore};
 
 mod bearer;
+mod cache;
+mod calendar;
 mod church;
+mod cli;
+mod diff;
 mod env;
+mod error;
+mod history;
+#[cfg(test)]
+mod mock;
 mod persons;
+mod report;
+mod retry;
 mod send;
 mod runcode;
+mod scoring;
+mod settings;
+mod skipped;
+mod trends;
+mod vault;
 
 #[tokio::main]
 async fn main() {
     env_logger::init(); // Initialize the logger
+    let cli = cli::Cli::parse();
+
+    if let Some(command) = cli.command {
+        let result = match command {
+            cli::Command::Open { path } => open_envelope(&path),
+            cli::Command::Vault { action } => env::vault_command(action),
+            cli::Command::Cache { action } => cache::cache_command(action),
+            cli::Command::Diff { from, to, json } => diff::diff_command(from, to, json),
+            cli::Command::History { area, days } => history::history_command(area, days),
+        };
+        if let Err(e) = result {
+            error!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
 
     info!("Starting the referral list process...");
     
@@ -31,18 +63,15 @@ async fn main() {
     };
     env_set_bar.set_style(ProgressStyle::default_bar().template("{spinner} {msg}").unwrap());
     env_set_bar.set_message("Loading .env data...");
-    let save_env = match runcode::check_for_runcode() {
+    let save_env = match runcode::check_for_runcode(cli.runcode) {
         Some(env) => {
             env
         }
         None => {
             let env = env::check_vars();
-            match runcode::build_base64_runcode_from_env(&env) {
-                Some(runcode) => {
-                    println!("Runcode: {} . You can also pass this as an argument to referral_list_endpoint.exe", runcode)
-                }
-                None => {}
-            };
+            if let Some(runcode) = runcode::build_base64_runcode_from_env(&env) {
+                println!("Runcode: {} . You can also pass this as an argument to referral_list_endpoint.exe", runcode)
+            }
             env
         }
     };
@@ -59,7 +88,17 @@ async fn main() {
     church_client_bar.set_style(ProgressStyle::default_bar().template("{spinner} {msg}").unwrap());
     church_client_bar.set_message("Loading Church Client data...");
     
-    let church_client = Arc::new(Mutex::new(church::ChurchClient::new(save_env).await.unwrap()));
+    let church_client = match church::ChurchClient::new(save_env).await {
+        Ok(mut church_client) => {
+            church_client.refresh.extend(cli.refresh);
+            cli.scoring.apply(&mut church_client.settings.scoring);
+            Arc::new(church_client)
+        }
+        Err(e) => {
+            error!("Unable to start the Church Client: {e}");
+            std::process::exit(e.exit_code());
+        }
+    };
     
     church_client_bar.inc(1);
     church_client_bar.finish_with_message("Church Client load finished!");
@@ -69,13 +108,36 @@ async fn main() {
     let result = send(Arc::clone(&m), Arc::clone(&church_client)).await;
     match result {
         Ok(_) => info!("Send operation completed successfully."),
-        Err(e) => error!("Error during send operation: {}", e),
+        Err(e) => {
+            error!("Error during send operation: {}", e);
+            std::process::exit(e.exit_code());
+        }
     }
 }
 
-async fn send(m: Arc<Mutex<MultiProgress>>, church_client: Arc<Mutex<ChurchClient>>) -> anyhow::Result<bool> {
+/// Prints the payload of a v2 envelope if it verifies under CRYPT_KEY
+fn open_envelope(path: &str) -> anyhow::Result<()> {
+    dotenvy::dotenv().ok();
+    let posted = if path == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(path)?
+    };
+    let crypt_key = match std::env::var("CRYPT_KEY") {
+        Ok(k) => k,
+        Err(_) => dialoguer::Password::new()
+            .with_prompt("Enter the CRYPT key the envelope was sealed with")
+            .interact()?,
+    };
+
+    let payload = send::open_envelope(&serde_json::from_str(&posted)?, &crypt_key)?;
+    println!("{}", serde_json::to_string_pretty(&payload)?);
+    Ok(())
+}
+
+async fn send(m: Arc<Mutex<MultiProgress>>, church_client: Arc<ChurchClient>) -> error::Result<bool> {
     info!("Fetching person data for timeline...");
-    let da_peeps = store_timeline(Arc::clone(&m), Arc::clone(&church_client)).await?;
+    let mut da_peeps = store_timeline(Arc::clone(&m), Arc::clone(&church_client)).await?;
 
     let send_bar = {
         let m = m.lock().await;
@@ -85,26 +147,50 @@ async fn send(m: Arc<Mutex<MultiProgress>>, church_client: Arc<Mutex<ChurchClien
     send_bar.set_message("Sending data...");
     debug!("Starting data conversion for {} people", da_peeps.len());
 
-    let out = persons::convert_referral_to_gas(da_peeps);
+    // Referrals no one has contacted go first, longest waiting first
+    da_peeps.sort_by_key(|p| std::cmp::Reverse(p.elapsed_time));
+
+    let working_path = std::path::Path::new(&church_client.env.working_path);
+    let aggregates = report::Aggregates::from_people(&da_peeps);
+    if let Err(e) = aggregates.save(working_path) {
+        warn!("Unable to save the report: {e}");
+    }
+
+    let mut payload = send::Payload {
+        referrals: persons::convert_referral_to_gas(da_peeps, &church_client.settings.outcomes),
+        ..Default::default()
+    };
+    if church_client.settings.payload.diff {
+        let cache = cache::SnapshotCache::new(working_path, church_client.settings.cache.clone());
+        // Sent even when there's nothing to compare yet, so the payload keeps its shape
+        payload.diff = Some(match diff::latest(&cache) {
+            Ok(diff) => diff.unwrap_or_default(),
+            Err(e) => {
+                warn!("Unable to diff the people list: {e}");
+                Default::default()
+            }
+        });
+    }
+    if church_client.settings.payload.aggregates {
+        payload.aggregates = Some(aggregates);
+    }
+    if church_client.settings.payload.trends {
+        // Sent empty when there's no history, so the payload keeps its shape
+        let trends = history::History::open(working_path)
+            .and_then(|history| trends::trends(&history, Utc::now(), &church_client.settings.trends));
+        payload.trends = Some(trends.unwrap_or_else(|e| {
+            warn!("Unable to work out trends from the history: {e}");
+            trends::Trends::default()
+        }));
+    }
 
     send_bar.inc(1);
 
-    let json_data = serde_json::to_value(&out)?;
+    let json_data = payload.to_value()?;
     send_bar.inc(1);
-    match send::send_to_google_apps_script(
-        json_data,
-        {
-            let church_client = church_client.lock().await;
-            church_client.env.timeline_send_url.clone()
-        },
-    )
-    .await
-    {
-        Ok(_) => info!("Data sent successfully to Google Apps Script."),
-        Err(e) => {
-            error!("Error sending request to Google Apps Script: {}", e);
-        }
-    }
+    let env = &church_client.env;
+    send::send_to_google_apps_script(json_data, env.timeline_send_url.clone(), &env.crypt_key, env.envelope_format).await?;
+    info!("Data sent successfully to Google Apps Script.");
     send_bar.inc(1);
     send_bar.finish_with_message("Data Sent!");
 
@@ -113,21 +199,19 @@ async fn send(m: Arc<Mutex<MultiProgress>>, church_client: Arc<Mutex<ChurchClien
 
 pub async fn store_timeline(
     m: Arc<Mutex<MultiProgress>>,
-    church_client: Arc<Mutex<ChurchClient>> // Now using tokio::sync::Mutex
-) -> anyhow::Result<Vec<persons::ReferralPerson>> {
+    church_client: Arc<ChurchClient>
+) -> error::Result<Vec<persons::ReferralPerson>> {
+    let started_at = Utc::now();
     info!("Fetching cached person list...");
-    let persons_list = {
-        let mut church_client = church_client.lock().await;
-        church_client.get_cached_people_list().await?.to_vec()
-    };
+    let all_people = church_client.get_cached_people_list().await?;
 
-    let now = Utc::now().naive_utc();
-    let persons_list: Vec<persons::Person> = persons_list
-        .into_iter()
+    let persons_list: Vec<persons::Person> = all_people
+        .iter()
         .filter(|x| {
             x.person_status < persons::PersonStatus::NewMember &&
-                now.signed_duration_since(x.assigned_date) < Duration::days(8)
+                started_at.signed_duration_since(x.assigned_date) < Duration::days(church_client.settings.scoring.lookback_days)
         })
+        .cloned()
         .collect();
 
     info!("Processing {} person records...", persons_list.len());
@@ -136,19 +220,25 @@ pub async fn store_timeline(
         let m = m.lock().await;
         m.add(ProgressBar::new(persons_list.len() as u64))
     };
-    person_overall_bar.set_style(ProgressStyle::default_bar().template("[{elapsed:4}] {wide_bar:.cyan/blue} [-{eta:4}] {percent}% {msg} ({pos}/{len})")?);
+    person_overall_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed:4}] {wide_bar:.cyan/blue} [-{eta:4}] {percent}% {msg} ({pos}/{len})")
+            .map_err(anyhow::Error::from)?,
+    );
     person_overall_bar.set_message("Retrieving/Processing person records...");
     person_overall_bar.enable_steady_tick(Dur::from_millis(1000));
 
-    let semaphore = Arc::new(Semaphore::new(3));
+    let semaphore = Arc::new(Semaphore::new(church_client.settings.concurrency.max(1)));
     let mut tasks = Vec::new();
 
     for person in persons_list {
         let m = Arc::clone(&m);
         let church_client = Arc::clone(&church_client);
         let semaphore = Arc::clone(&semaphore);
+        let this_person = person.clone();
         let task = tokio::spawn(async move {
-            let _permit = semaphore.acquire().await.unwrap();
+            // The semaphore is never closed
+            let _permit = semaphore.acquire().await;
 
             let person_bar = {
                 let m = m.lock().await;
@@ -158,120 +248,212 @@ pub async fn store_timeline(
             person_bar.set_message(format!("Processing person: {}", person.first_name));
             person_bar.enable_steady_tick(Dur::from_millis(100));
 
-            let t: Vec<persons::TimelineEvent> = {
-                let mut church_client = church_client.lock().await;
-                if let Ok(t) = church_client.get_person_timeline(&person).await {
-                    t.iter()
-                        .filter(|event| matches!(
-                            event.item_type,
-                            persons::TimelineItemType::Contact |
-                            persons::TimelineItemType::Teaching |
-                            persons::TimelineItemType::NewReferral
-                        ) &&
-                        (if event.item_type != persons::TimelineItemType::NewReferral && event.status.is_none() {
-                            false
-                        } else {
-                            true
-                        }))
-                        .cloned()
-                        .collect()
-                } else {
-                    return None;
-                }
-            };
-
-            let cont_time = {
-                let mut church_client = church_client.lock().await;
-                match church_client.get_person_contact_time(&person).await {
-                    Ok(Some(t)) => t,
-                    _ => return None,
+            let done = match church_client.get_person_timeline(&person).await {
+                Ok(timeline) => {
+                    let scored = score_person(&person, &timeline, &church_client.settings.scoring);
+                    (Some(timeline), scored)
                 }
+                Err(e) => (None, Err(skipped::SkipReason::from_timeline_error(&e))),
             };
 
-            let mut this_guy = persons::ReferralPerson::new(
-                person.guid,
-                person.first_name,
-                cont_time,
-                t.clone(),
-                person.area_name.unwrap_or_else(|| String::from("default_area")),
-                match person.referral_status {
-                    persons::ReferralStatus::NotAttempted => "Not Attempted",
-                    persons::ReferralStatus::NotSuccessful => "Unsuccessful",
-                    persons::ReferralStatus::Successful => "Successful",
-                }.to_string()
-            );
-
-            let yesterday = chrono::Local::now().naive_utc().date() - Duration::days(1);
-            let last_new_referral = t.iter().find(|event| event.item_type == persons::TimelineItemType::NewReferral);
-            let mut current_date = last_new_referral.unwrap().item_date.date();
-            let mut contact_days = 0;
-            let mut total_days = 0;
-            this_guy.referral_status = "Not Attempted".to_string();
-
-            while current_date <= yesterday && total_days < 7 {
-                total_days += 1;
-
-                let c = check_day(current_date, t.clone());
-                if c == -1 {
-                    contact_days += 1;
-                    this_guy.referral_status = "Successful".to_string();
-                    break;
-                } else {
-                    contact_days += c;
-                    if !(this_guy.referral_status == "Successful") && contact_days==1 { this_guy.referral_status = "Unsuccessful".to_string()};
-                }
-
-                current_date = current_date + Duration::days(1);
-            }
-
-            this_guy.set_score(format!("{contact_days}/{total_days}"));
-
             person_bar.finish_and_clear();
-            Some(this_guy)
+            done
         });
 
-        tasks.push(task);
+        tasks.push((this_person, task));
     }
 
     let mut da_peeps = Vec::new();
-    for task in tasks {
-        match task.await.unwrap() {
-            Some(person) => {
-                da_peeps.push(person);
-            }
-            None => {
-                debug!("Person task did not return valid data.");
+    let mut timelines = Vec::new();
+    let mut skipped = Vec::new();
+    for (person, task) in tasks {
+        let (timeline, scored) = match task.await {
+            Ok(done) => done,
+            Err(e) => (None, Err(skipped::SkipReason::Crashed(e.to_string()))),
+        };
+        if let Some(timeline) = timeline {
+            timelines.push((person.guid.clone(), timeline));
+        }
+        match scored {
+            Ok(rows) => da_peeps.extend(rows),
+            Err(reason) => {
+                debug!("Skipped {}: {}", person.guid, reason.label());
+                skipped.push(skipped::Skipped::new(&person, reason));
             }
         }
         person_overall_bar.inc(1);
     }
 
     person_overall_bar.finish_with_message("Person Records Processed!");
+    let summary = skipped::summary(da_peeps.len(), &skipped);
+    info!("{summary}");
+    if let Err(e) = m.lock().await.println(&summary) {
+        debug!("Unable to print the summary: {e}");
+    }
+
     info!("Saving processed data...");
-    let church_client = church_client.lock().await;
+    let working_path = std::path::Path::new(&church_client.env.working_path);
     church_client.env.save_data(&da_peeps)?;
+    if let Err(e) = skipped::save(working_path, &skipped) {
+        warn!("Unable to save the skipped list: {e}");
+    }
+
+    if church_client.settings.history {
+        let record = history::RunRecord {
+            started_at,
+            people: &all_people,
+            timelines: &timelines,
+            results: &da_peeps,
+        };
+        match history::History::open(working_path)
+            .and_then(|mut history| history.record_run(&record))
+        {
+            Ok(run_id) => info!("Recorded run {run_id} in the history"),
+            Err(e) => warn!("Unable to record this run in the history: {e}"),
+        }
+    }
 
     info!("Processed data successfully saved.");
     Ok(da_peeps)
 }
 
-fn check_day(day: chrono::naive::NaiveDate, person: Vec<persons::TimelineEvent>) -> i32 {
-    let events_on_day: Vec<&persons::TimelineEvent> = person
-        .iter()
-        .filter(|event| event.item_date.date() == day
-            && (event.item_type == persons::TimelineItemType::Contact
-                || event.item_type == persons::TimelineItemType::Teaching))
-        .collect();
+/// Scores each of a referral's episodes, one row each. Episodes referred before
+/// the lookback are left out, except the latest, and so are earlier episodes
+/// where no one contacted them. If no one has contacted them since the latest
+/// referral, that row is marked not contacted with the time since they were assigned.
+/// Without a new referral in their timeline there's nothing to score, so they're skipped.
+fn score_person(
+    person: &persons::Person,
+    timeline: &[persons::TimelineEvent],
+    rules: &scoring::ScoringRules,
+) -> Result<Vec<persons::ReferralPerson>, skipped::SkipReason> {
+    let today = rules.today();
+    let mut episodes = scoring::score_episodes(timeline, today, rules);
+    let Some(latest) = episodes.pop() else {
+        return Err(skipped::SkipReason::NoReferralEvent);
+    };
+    episodes.retain(|e| {
+        e.contact.first_attempt_minutes.is_some()
+            && today.signed_duration_since(e.referred_on) < Duration::days(rules.lookback_days)
+    });
+    episodes.push(latest);
 
-    if events_on_day.is_empty() {
-        return 0;
-    }
+    let rows = episodes
+        .into_iter()
+        .map(|score| {
+            let contact_time = score.contact.first_attempt_minutes;
+            let fraction = score.fraction();
+            let mut this_guy = persons::ReferralPerson::new(
+                person.guid.clone(),
+                person.first_name.clone(),
+                contact_time,
+                score.events,
+                person.area_name.clone().unwrap_or_else(|| String::from("default_area")),
+                score.outcome,
+            );
+            this_guy.set_score(fraction);
+            this_guy.set_days(score.contacted_days, score.window_days);
+            this_guy.set_referral_date(score.referred_on);
+            this_guy.set_location(person.zone_name.clone(), person.district_id);
+            this_guy.set_success(score.contact.first_success_minutes, score.contact.attempts);
+            this_guy.set_working_times(
+                score.contact.first_attempt_working_minutes,
+                score.contact.first_success_working_minutes,
+            );
+            if contact_time.is_none() {
+                let elapsed = Utc::now().signed_duration_since(person.assigned_date).num_minutes();
+                this_guy.set_not_contacted(elapsed.max(0) as usize);
+            }
+            this_guy
+        })
+        .collect();
+    Ok(rows)
+}
 
-    for event in events_on_day {
-        if event.status.unwrap_or(false) {
-            return -1;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{event, person, MockChurch};
+    use wiremock::{matchers, Mock, ResponseTemplate};
+
+    #[tokio::test]
+    async fn pipeline_runs_against_mock() {
+        let assigned = Utc::now() - Duration::days(3);
+        let people = vec![
+            person("guid-1", "Alex", "Riverside", assigned),
+            // Assigned too long ago to be scored
+            person("guid-2", "Sam", "Hillcrest", assigned - Duration::days(30)),
+            person("no-referral", "Kim", "Riverside", assigned),
+            person("not-contacted", "Lee", "Riverside", assigned),
+            person("no-timeline", "Jo", "Riverside", assigned),
+        ];
+        let timelines = vec![
+            (
+                "guid-1".to_string(),
+                vec![
+                    event("CONTACT", assigned + Duration::hours(2), Some(true)),
+                    event("NEW_REFERRAL", assigned, None),
+                ],
+            ),
+            ("no-referral".to_string(), vec![event("CONTACT", assigned, Some(false))]),
+            ("not-contacted".to_string(), vec![event("NEW_REFERRAL", assigned, None)]),
+        ];
+        let mock = MockChurch::start(people, timelines, false).await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/exec"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .mount(&mock.server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut env = mock.env(dir.path());
+        env.crypt_key = "key".to_string();
+        env.envelope_format = send::EnvelopeFormat::V2;
+        let church_client = Arc::new(ChurchClient::with_urls(env, mock.urls()).await.unwrap());
+        let m = Arc::new(Mutex::new(MultiProgress::with_draw_target(
+            indicatif::ProgressDrawTarget::hidden(),
+        )));
+
+        send(m, church_client).await.unwrap();
+        assert!(dir.path().join("data.json").exists());
+        assert!(dir.path().join(report::REPORT_FILE).exists());
+        let skipped: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.path().join(skipped::SKIPPED_FILE)).unwrap()).unwrap();
+        let reasons: Vec<(&str, &str)> = skipped
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| (s["guid"].as_str().unwrap(), s["reason"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                ("no-referral", "no_referral_event"),
+                ("no-timeline", "timeline_fetch_failed"),
+            ]
+        );
+        let runs = history::History::open(dir.path())
+            .unwrap()
+            .area_runs(Some("Riverside"), Utc::now() - Duration::days(1))
+            .unwrap();
+        assert_eq!((runs.len(), runs[0].referrals, runs[0].successful), (1, 2, 1));
+
+        let posts = mock.server.received_requests().await.unwrap();
+        let post = posts.iter().find(|r| r.url.path() == "/exec").unwrap();
+        let envelope: serde_json::Value = serde_json::from_slice(&post.body).unwrap();
+        let payload = send::open_envelope(&envelope, "key").unwrap();
+        let rows = payload.as_array().unwrap();
+        assert_eq!(rows.len(), 2);
+        // Not contacted yet, so listed first with how long they've waited
+        assert_eq!(rows[0]["name"], "Lee");
+        assert_eq!(rows[0]["referral_status"], "Not Contacted");
+        assert_eq!(rows[0]["contact_time"], serde_json::Value::Null);
+        assert!((rows[0]["elapsed_time"].as_f64().unwrap() - 3.0).abs() < 0.01);
+        assert_eq!(rows[1]["name"], "Alex");
+        assert_eq!(rows[1]["referral_status"], "Successful");
+        assert_eq!(rows[1]["success_time"], rows[1]["contact_time"]);
+        assert_eq!(rows[1]["elapsed_time"], serde_json::Value::Null);
+        assert_eq!(rows[1]["attempts"], 0);
+        assert_eq!(rows[1]["referral_date"], serde_json::json!(scoring::ScoringRules::default().today() - Duration::days(3)));
     }
-
-    1
 }
@@ -0,0 +1,232 @@
+// In-process stand-in for referral manager and the Okta IDX login, so the
+// whole pipeline can be exercised without church credentials.
+
+use std::path::Path;
+
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{json, Value};
+use totp_rs::{Algorithm, Secret, TOTP};
+use wiremock::{
+    matchers::{body_partial_json, header, header_exists, method, path},
+    Mock, MockServer, Request, ResponseTemplate,
+};
+
+use crate::{church::ServiceUrls, env::Env};
+
+pub const USERNAME: &str = "elder.test";
+pub const PASSWORD: &str = "hunter2";
+pub const MISSION_ID: usize = 7;
+/// RFC 6238 test seed
+pub const TOTP_SEED: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+pub struct MockChurch {
+    pub server: MockServer,
+}
+
+impl MockChurch {
+    /// Starts a server that logs in with [`USERNAME`]/[`PASSWORD`], and with a
+    /// TOTP code from [`TOTP_SEED`] as well when `require_totp` is set.
+    pub async fn start(persons: Vec<Value>, timelines: Vec<(String, Vec<Value>)>, require_totp: bool) -> Self {
+        let server = MockServer::start().await;
+        let uri = server.uri();
+
+        // Login page with the state token embedded the way Okta escapes it
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"<html><script>var config = {"stateToken":"state\x2Dtoken\x3D","other":1};</script></html>"#,
+            ))
+            .mount(&server)
+            .await;
+
+        Mock::given(method("POST"))
+            .and(path("/idp/idx/introspect"))
+            .and(body_partial_json(json!({ "stateToken": "state-token=" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "stateHandle": "sh-introspect" })))
+            .mount(&server)
+            .await;
+
+        Mock::given(method("POST"))
+            .and(path("/idp/idx/identify"))
+            .and(body_partial_json(json!({ "identifier": USERNAME })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(select_authenticator(&["aut-pass", "aut-totp"])))
+            .mount(&server)
+            .await;
+
+        for (id, kind) in [("aut-pass", "password"), ("aut-totp", "app")] {
+            Mock::given(method("POST"))
+                .and(path("/idp/idx/challenge"))
+                .and(body_partial_json(json!({ "authenticator": { "id": id } })))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "stateHandle": format!("sh-challenge-{kind}"),
+                    "remediation": { "value": [{ "name": "challenge-authenticator" }] },
+                    "currentAuthenticatorEnrollment": { "value": authenticator(id) }
+                })))
+                .mount(&server)
+                .await;
+        }
+
+        let success = json!({ "success": { "href": format!("{uri}/login/callback") } });
+        let after_password = if require_totp {
+            select_authenticator(&["aut-totp"])
+        } else {
+            success.clone()
+        };
+        Mock::given(method("POST"))
+            .and(path("/idp/idx/challenge/answer"))
+            .and(body_partial_json(json!({
+                "stateHandle": "sh-challenge-password",
+                "credentials": { "passcode": PASSWORD }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(after_password))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/idp/idx/challenge/answer"))
+            .and(body_partial_json(json!({ "stateHandle": "sh-challenge-app" })))
+            .and(|req: &Request| {
+                let body: Value = serde_json::from_slice(&req.body).unwrap_or_default();
+                body["credentials"]["passcode"]
+                    .as_str()
+                    .is_some_and(|code| totp().check_current(code).unwrap_or(false))
+            })
+            .respond_with(ResponseTemplate::new(200).set_body_json(success))
+            .mount(&server)
+            .await;
+        // Anything else sent as an answer is wrong
+        Mock::given(method("POST"))
+            .and(path("/idp/idx/challenge/answer"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "stateHandle": "sh-rejected",
+                "messages": { "value": [{ "class": "ERROR", "message": "Password is incorrect" }] },
+                "remediation": { "value": [{ "name": "challenge-authenticator" }] }
+            })))
+            .with_priority(10)
+            .mount(&server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/login/callback"))
+            .respond_with(ResponseTemplate::new(200).insert_header("Set-Cookie", "rm_session=mock; Path=/"))
+            .mount(&server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/services/auth"))
+            .and(header_exists("cookie"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "token": bearer_token(Utc::now() + Duration::hours(1)) })),
+            )
+            .mount(&server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path(format!("/services/people/mission/{MISSION_ID}")))
+            .and(|req: &Request| {
+                req.headers
+                    .get("authorization")
+                    .and_then(|h| h.to_str().ok())
+                    .is_some_and(|h| h.starts_with("Bearer ") && h.len() > "Bearer ".len())
+            })
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "persons": persons })))
+            .mount(&server)
+            .await;
+
+        for (guid, events) in timelines {
+            Mock::given(method("GET"))
+                .and(path(format!("/services/progress/timeline/{guid}")))
+                .and(header("cookie", "rm_session=mock"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(Value::Array(events)))
+                .mount(&server)
+                .await;
+        }
+
+        Self { server }
+    }
+
+    pub fn urls(&self) -> ServiceUrls {
+        ServiceUrls {
+            referral_manager: self.server.uri(),
+            identity: self.server.uri(),
+        }
+    }
+
+    /// Settings for a client logging into this server
+    pub fn env(&self, working_path: &Path) -> Env {
+        Env {
+            church_username: USERNAME.to_string(),
+            church_password: PASSWORD.to_string(),
+            timeline_send_url: format!("{}/exec", self.server.uri()),
+            working_path: working_path.to_string_lossy().to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Paths of every request the server has seen, in order
+    pub async fn requested_paths(&self) -> Vec<String> {
+        self.server
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .iter()
+            .map(|r| r.url.path().to_string())
+            .collect()
+    }
+}
+
+fn totp() -> TOTP {
+    TOTP::new_unchecked(Algorithm::SHA1, 6, 1, 30, Secret::Encoded(TOTP_SEED.to_string()).to_bytes().unwrap())
+}
+
+fn authenticator(id: &str) -> Value {
+    match id {
+        "aut-pass" => json!({ "id": id, "type": "password", "key": "okta_password", "methods": [{ "type": "password" }] }),
+        _ => json!({ "id": id, "type": "app", "key": "google_otp", "methods": [{ "type": "otp" }] }),
+    }
+}
+
+fn select_authenticator(ids: &[&str]) -> Value {
+    json!({
+        "stateHandle": "sh-select",
+        "remediation": { "value": [{ "name": "select-authenticator-authenticate" }] },
+        "authenticators": { "value": ids.iter().map(|id| authenticator(id)).collect::<Vec<_>>() }
+    })
+}
+
+/// An unsigned JWT with the claims referral manager hands out
+pub fn bearer_token(expires: DateTime<Utc>) -> String {
+    let claims = json!({
+        "missionId": MISSION_ID,
+        "exp": expires.timestamp(),
+        "iat": (expires - Duration::hours(1)).timestamp(),
+    });
+    let claims = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(claims.to_string());
+    format!("eyJhbGciOiJub25lIn0.{claims}.sig")
+}
+
+/// A people list entry
+pub fn person(guid: &str, first_name: &str, area: &str, assigned: DateTime<Utc>) -> Value {
+    json!({
+        "personGuid": guid,
+        "firstName": first_name,
+        "referralStatusId": 10,
+        "personStatusId": 1,
+        "missionId": MISSION_ID,
+        "zoneId": 1,
+        "zoneName": "Mock Zone",
+        "districtId": 11,
+        "areaName": area,
+        "referralAssignedDate": assigned.timestamp_millis(),
+    })
+}
+
+/// A timeline entry
+pub fn event(item_type: &str, date: DateTime<Utc>, status: Option<bool>) -> Value {
+    json!({
+        "timelineItemType": item_type,
+        "itemDate": date.timestamp_millis(),
+        "eventStatus": status,
+    })
+}
@@ -1,35 +1,50 @@
 // Jackson Coxson, Karter Arritt, & Adam Morgan
 
-use chrono::naive::serde::ts_milliseconds;
-use chrono::{ NaiveDateTime, Duration };
+use chrono::serde::ts_milliseconds;
+use chrono::{ DateTime, NaiveDate, Utc };
 use log::warn;
 use serde::{ Deserialize, Serialize };
 use serde_repr::{ Deserialize_repr, Serialize_repr };
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Persons {
-    persons: Vec<Person>,
-}
+use crate::scoring::Outcome;
+use crate::send::{ OutcomeFormat, OutcomeValue };
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ReferralPerson {
     pub id: String,
     pub name: String,
-    pub contact_time: usize,
+    /// Minutes to the first contact. None when no one has contacted them yet.
+    pub contact_time: Option<usize>,
     pub events: Vec<TimelineEvent>,
     pub score: String,
+    /// What `score` shows: days in the window with a contact, and days scored so far
+    pub contacted_days: u32,
+    pub window_days: u32,
     pub area: String,
-    pub referral_status: String,
+    pub zone: Option<String>,
+    pub district_id: Option<usize>,
+    /// Day of the new referral this row scores. People referred more than once get a row each time.
+    pub referral_date: Option<NaiveDate>,
+    pub referral_status: Outcome,
+    /// Minutes since they were assigned, for people no one has contacted yet
+    pub elapsed_time: Option<usize>,
+    /// Minutes to the first successful contact
+    pub success_time: Option<usize>,
+    /// Contacts before the first success
+    pub attempts: u32,
+    /// `contact_time` and `success_time` counting only working hours, when they're set
+    pub working_contact_time: Option<usize>,
+    pub working_success_time: Option<usize>,
 }
 
 impl ReferralPerson {
     pub fn new(
         id: String,
         name: String,
-        contact_time: usize,
+        contact_time: Option<usize>,
         events: Vec<TimelineEvent>,
         area: String,
-        referral_status: String
+        referral_status: Outcome
     ) -> ReferralPerson {
         ReferralPerson {
             id,
@@ -37,26 +52,66 @@ impl ReferralPerson {
             contact_time,
             events,
             score: "0/0".to_string(),
+            contacted_days: 0,
+            window_days: 0,
             area,
+            zone: None,
+            district_id: None,
+            referral_date: None,
             referral_status,
+            elapsed_time: None,
+            success_time: None,
+            attempts: 0,
+            working_contact_time: None,
+            working_success_time: None,
         }
     }
     pub fn set_score(&mut self, score: String) {
         self.score = score;
     }
+    pub fn set_days(&mut self, contacted_days: u32, window_days: u32) {
+        self.contacted_days = contacted_days;
+        self.window_days = window_days;
+    }
+    pub fn set_success(&mut self, success_time: Option<usize>, attempts: u32) {
+        self.success_time = success_time;
+        self.attempts = attempts;
+    }
+    pub fn set_not_contacted(&mut self, elapsed_time: usize) {
+        self.contact_time = None;
+        self.referral_status = Outcome::NotContacted;
+        self.elapsed_time = Some(elapsed_time);
+    }
+    pub fn set_referral_date(&mut self, referral_date: NaiveDate) {
+        self.referral_date = Some(referral_date);
+    }
+    pub fn set_location(&mut self, zone: Option<String>, district_id: Option<usize>) {
+        self.zone = zone;
+        self.district_id = district_id;
+    }
+    pub fn set_working_times(&mut self, contact_time: Option<usize>, success_time: Option<usize>) {
+        self.working_contact_time = contact_time;
+        self.working_success_time = success_time;
+    }
 }
 
-pub fn convert_referral_to_gas(referral_people: Vec<ReferralPerson>) -> Vec<GASPerson> {
+pub fn convert_referral_to_gas(referral_people: Vec<ReferralPerson>, outcomes: &OutcomeFormat) -> Vec<GASPerson> {
     referral_people
         .into_iter()
         .map(|referral_person| {
-            let contact_time = (referral_person.contact_time as f64) / 1440.0; // Convert minutes to decimal days
+            let contact_time = referral_person.contact_time.map(|minutes| (minutes as f64) / 1440.0); // Convert minutes to decimal days
             GASPerson {
                 name: referral_person.name,
                 contact_time, // Decimal days
                 score: referral_person.score,
                 area: referral_person.area,
-                referral_status: referral_person.referral_status,
+                referral_status: outcomes.value(referral_person.referral_status),
+                success_time: referral_person.success_time.map(|minutes| (minutes as f64) / 1440.0),
+                attempts: referral_person.attempts,
+                working_contact_time: referral_person.working_contact_time.map(|minutes| (minutes as f64) / 1440.0),
+                working_success_time: referral_person.working_success_time.map(|minutes| (minutes as f64) / 1440.0),
+                referral_date: referral_person.referral_date,
+                elapsed_time: referral_person.elapsed_time.map(|minutes| (minutes as f64) / 1440.0),
             }
         })
         .collect()
@@ -65,10 +120,16 @@ pub fn convert_referral_to_gas(referral_people: Vec<ReferralPerson>) -> Vec<GASP
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GASPerson {
     pub name: String,
-    pub contact_time: f64, // Store the contact time in decimal days
+    pub contact_time: Option<f64>, // Store the contact time in decimal days, null until they're contacted
     pub score: String,
     pub area: String,
-    pub referral_status: String,
+    pub referral_status: OutcomeValue,
+    pub success_time: Option<f64>, // Decimal days, null until they're reached
+    pub attempts: u32,
+    pub working_contact_time: Option<f64>, // Decimal days of working hours, null without working hours set
+    pub working_success_time: Option<f64>,
+    pub referral_date: Option<NaiveDate>,
+    pub elapsed_time: Option<f64>, // Decimal days since they were assigned, for people not contacted yet
 }
 
 // impl GASPerson {
@@ -115,7 +176,7 @@ pub struct Person {
 
     #[serde(rename = "referralAssignedDate")]
     #[serde(with = "ts_milliseconds")]
-    pub assigned_date: NaiveDateTime,
+    pub assigned_date: DateTime<Utc>,
 }
 
 impl Person {
@@ -136,14 +197,14 @@ impl Person {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct TimelineEvent {
     #[serde(rename = "timelineItemType")]
     pub item_type: TimelineItemType,
 
     #[serde(rename = "itemDate")]
     #[serde(with = "ts_milliseconds")]
-    pub item_date: NaiveDateTime,
+    pub item_date: DateTime<Utc>,
 
     #[serde(rename = "eventStatus")]
     pub status: Option<bool>,
@@ -165,15 +226,6 @@ impl TimelineEvent {
             Vec::new()
         }
     }
-
-    // Convert MST (UTC-7) to EST (UTC-5)
-    pub fn convert_mst_to_est(&mut self) {
-        //println!("Initial NaiveDateTime (MST): {}", self.item_date);
-
-        self.item_date = self.item_date - Duration::hours(5); //adjust from MST to EST
-
-        //println!("Converted NaiveDateTime (EST): {}", self.item_date);
-    }
 }
 
 #[derive(Serialize_repr, Deserialize_repr, PartialEq, Clone, Debug)]
@@ -0,0 +1,133 @@
+// Statistics for each area, district and zone, sent along with the rows and
+// saved to report.json in the working path
+
+use std::{collections::BTreeMap, path::Path};
+
+use serde::Serialize;
+
+use crate::{persons::ReferralPerson, scoring::Outcome};
+
+pub const REPORT_FILE: &str = "report.json";
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Aggregates {
+    pub areas: Vec<GroupStats>,
+    pub districts: Vec<GroupStats>,
+    pub zones: Vec<GroupStats>,
+}
+
+/// How one area, district or zone is doing. Times are decimal days, like the rows.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct GroupStats {
+    pub name: String,
+    pub referrals: usize,
+    pub successful: usize,
+    /// Referrals no one has contacted yet, which contact times leave out
+    pub not_contacted: usize,
+    pub success_rate: f64,
+    pub median_contact_time: Option<f64>,
+    pub p90_contact_time: Option<f64>,
+    /// Mean of each referral's contacted days over days scored
+    pub average_score: Option<f64>,
+}
+
+impl Aggregates {
+    pub fn from_people(people: &[ReferralPerson]) -> Self {
+        Self {
+            areas: group(people, |p| p.area.clone()),
+            districts: group(people, |p| match p.district_id {
+                Some(id) => format!("District {id}"),
+                None => "No district".to_string(),
+            }),
+            zones: group(people, |p| p.zone.clone().unwrap_or_else(|| "No zone".to_string())),
+        }
+    }
+
+    pub fn save(&self, working_path: &Path) -> anyhow::Result<()> {
+        let file = std::fs::File::create(working_path.join(REPORT_FILE))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+}
+
+/// Groups sorted by name
+fn group(people: &[ReferralPerson], key: impl Fn(&ReferralPerson) -> String) -> Vec<GroupStats> {
+    let mut groups: BTreeMap<String, Vec<&ReferralPerson>> = BTreeMap::new();
+    for person in people {
+        groups.entry(key(person)).or_default().push(person);
+    }
+    groups.into_iter().map(|(name, people)| GroupStats::new(name, &people)).collect()
+}
+
+impl GroupStats {
+    fn new(name: String, people: &[&ReferralPerson]) -> Self {
+        let successful = people.iter().filter(|p| p.referral_status == Outcome::Successful).count();
+        let mut contact_times: Vec<f64> =
+            people.iter().filter_map(|p| p.contact_time).map(|minutes| minutes as f64 / 1440.0).collect();
+        contact_times.sort_by(f64::total_cmp);
+        let scores: Vec<f64> = people.iter().filter_map(|p| score_ratio(p)).collect();
+        Self {
+            name,
+            referrals: people.len(),
+            successful,
+            not_contacted: people.iter().filter(|p| p.referral_status == Outcome::NotContacted).count(),
+            success_rate: successful as f64 / people.len().max(1) as f64,
+            median_contact_time: percentile(&contact_times, 50.0),
+            p90_contact_time: percentile(&contact_times, 90.0),
+            average_score: (!scores.is_empty()).then(|| scores.iter().sum::<f64>() / scores.len() as f64),
+        }
+    }
+}
+
+/// Nearest rank percentile of sorted values
+fn percentile(sorted: &[f64], p: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// A `3/4` score as 0.75. Scores with no days scored yet have no ratio.
+fn score_ratio(person: &ReferralPerson) -> Option<f64> {
+    (person.window_days > 0).then(|| person.contacted_days as f64 / person.window_days as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn referral(area: &str, zone: &str, district: usize, minutes: usize, days: (u32, u32), outcome: Outcome) -> ReferralPerson {
+        let mut person =
+            ReferralPerson::new(area.to_string(), area.to_string(), Some(minutes), Vec::new(), area.to_string(), outcome);
+        person.set_days(days.0, days.1);
+        person.set_location(Some(zone.to_string()), Some(district));
+        person
+    }
+
+    #[test]
+    fn groups_by_area_district_and_zone() {
+        let day = 1440;
+        let people = vec![
+            referral("Riverside", "North", 1, day, (1, 1), Outcome::Successful),
+            referral("Riverside", "North", 1, 3 * day, (1, 2), Outcome::Unsuccessful),
+            referral("Lakeside", "North", 2, 2 * day, (0, 0), Outcome::NotAttempted),
+            referral("Hillcrest", "South", 3, 10 * day, (2, 4), Outcome::Successful),
+        ];
+        let aggregates = Aggregates::from_people(&people);
+
+        let names = |groups: &[GroupStats]| groups.iter().map(|g| g.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&aggregates.areas), vec!["Hillcrest", "Lakeside", "Riverside"]);
+        assert_eq!(names(&aggregates.districts), vec!["District 1", "District 2", "District 3"]);
+        assert_eq!(names(&aggregates.zones), vec!["North", "South"]);
+
+        let riverside = &aggregates.areas[2];
+        assert_eq!((riverside.referrals, riverside.successful, riverside.success_rate), (2, 1, 0.5));
+        assert_eq!(riverside.average_score, Some(0.75));
+
+        let north = &aggregates.zones[0];
+        assert_eq!(north.referrals, 3);
+        assert_eq!((north.median_contact_time, north.p90_contact_time), (Some(2.0), Some(3.0)));
+        assert_eq!(aggregates.areas[1].average_score, None);
+    }
+}
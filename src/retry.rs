@@ -0,0 +1,119 @@
+// Retry and backoff policy shared by every request to church servers
+
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts per request, the first one included
+    pub max_attempts: u32,
+    /// Backoff before the first retry. It doubles on every retry after that.
+    pub base_delay_ms: u64,
+    /// Backoff never grows past this
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 500,
+            max_delay_ms: 30_000,
+        }
+    }
+}
+
+/// What to do after a failed attempt
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Wait this long, then try again
+    Retry(Duration),
+    /// Log in again, then try again straight away
+    Reauthenticate,
+    /// Give up and return the error
+    Fail,
+}
+
+impl RetryPolicy {
+    /// Classifies the error from attempt number `attempt` (starting at 1)
+    pub fn classify(&self, error: &Error, attempt: u32, reauthenticated: bool) -> Verdict {
+        if attempt >= self.max_attempts {
+            return Verdict::Fail;
+        }
+        match error {
+            // 401/403, or a login page where JSON should be, mean the session is gone
+            Error::AuthenticationFailed(_) | Error::SchemaDrift(_) if !reauthenticated => Verdict::Reauthenticate,
+            Error::RateLimited { retry_after, .. } => {
+                let retry_after = Duration::from_secs(retry_after.unwrap_or_default());
+                Verdict::Retry(self.backoff(attempt).max(retry_after))
+            }
+            Error::Server { status, .. } if status.is_server_error() => Verdict::Retry(self.backoff(attempt)),
+            Error::Network(_) => Verdict::Retry(self.backoff(attempt)),
+            _ => Verdict::Fail,
+        }
+    }
+
+    /// Exponential backoff with full jitter
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay_ms
+            .saturating_mul(1u64 << attempt.saturating_sub(1).min(32))
+            .min(self.max_delay_ms);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    fn server(status: u16) -> Error {
+        Error::from_status(StatusCode::from_u16(status).unwrap(), "url", None)
+    }
+
+    #[test]
+    fn reauthenticates_once_on_401_and_403() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.classify(&server(401), 1, false), Verdict::Reauthenticate);
+        assert_eq!(policy.classify(&server(403), 1, false), Verdict::Reauthenticate);
+        assert_eq!(policy.classify(&server(401), 2, true), Verdict::Fail);
+    }
+
+    #[test]
+    fn backs_off_on_429_and_5xx() {
+        let policy = RetryPolicy::default();
+        assert!(matches!(policy.classify(&server(503), 1, false), Verdict::Retry(_)));
+        assert!(matches!(policy.classify(&server(500), 2, true), Verdict::Retry(_)));
+
+        let limited = Error::from_status(StatusCode::TOO_MANY_REQUESTS, "url", Some(7));
+        assert!(matches!(policy.classify(&limited, 1, false), Verdict::Retry(d) if d >= Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn fails_fast_on_permanent_errors_and_exhaustion() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.classify(&server(404), 1, false), Verdict::Fail);
+        assert_eq!(policy.classify(&server(400), 1, false), Verdict::Fail);
+        assert_eq!(policy.classify(&Error::MfaRequired(String::new()), 1, false), Verdict::Fail);
+        assert_eq!(policy.classify(&server(503), 3, false), Verdict::Fail);
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay_ms: 100,
+            max_delay_ms: 1_000,
+        };
+        for attempt in 1..40 {
+            let ceiling = (100u64 << (attempt - 1).min(32)).min(1_000);
+            assert!(policy.backoff(attempt) <= Duration::from_millis(ceiling));
+        }
+    }
+}
@@ -1,15 +1,39 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
 use crate::env::Env;
-use base64;
+use crate::vault::{self, Sealed};
 use base64::{engine::general_purpose, Engine};
-use dialoguer::{theme::ColorfulTheme, Input, Select};
-use serde_json; // Assuming the Env struct is in `env.rs`
+use dialoguer::{theme::ColorfulTheme, Input, Password, Select};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+/// Prefix of encrypted runcodes. Legacy runcodes are standard base64, which never contains a '.'
+pub const RUNCODE_PREFIX: &str = "rc2.";
+pub const KEY_FILE: &str = "runcode.key";
+
+/// What an `rc2.` runcode decodes to
+#[derive(Debug, Serialize, Deserialize)]
+struct RuncodeV2 {
+    /// Set when the runcode is locked with a key file instead of a passphrase
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_file: Option<PathBuf>,
+    sealed: Sealed,
+}
 
-pub fn check_for_runcode() -> Option<Env> {
-    let args: Vec<String> = std::env::args().collect();
+/// How a new runcode is locked
+pub enum RuncodeLock<'a> {
+    Passphrase(&'a str),
+    /// 32 random bytes in a file only this machine has
+    KeyFile(&'a Path),
+}
 
-    let base64_string: String = if args.len() >= 2 {
-        println!("Running with Runcode: {}", args[1]);
-        args[1].clone()
+pub fn check_for_runcode(runcode: Option<String>) -> Option<Env> {
+    let runcode: String = if let Some(runcode) = runcode {
+        println!("Running with a Runcode");
+        runcode
     } else {
         Input::with_theme(&ColorfulTheme::default())
             .with_prompt("Runcode? If unsure, <enter>")
@@ -19,68 +43,217 @@ pub fn check_for_runcode() -> Option<Env> {
     };
 
     // If the user just presses Enter and defaults to "/", return None
-    if base64_string == "/" {
+    if runcode == "/" {
         return None;
     }
 
-    // Decode the base64 string
-    match general_purpose::STANDARD.decode(&base64_string) {
-        Ok(decoded) => {
-            // Convert the decoded bytes into a UTF-8 string
-            if let Ok(decoded_str) = String::from_utf8(decoded) {
-                // Try to parse the JSON string into an Env struct
-                match build_env_from_runcode(&decoded_str) {
-                    Ok(env) => {
-                        return Some(env);
-                    }
-                    Err(e) => {
-                        println!("Error parsing JSON into Env: {}", e);
-                        return None;
-                    }
-                }
-            } else {
-                println!("Decoded data is not valid UTF-8");
-                return None;
-            }
-        }
+    match decode_runcode(runcode.trim(), prompt_passphrase) {
+        Ok(env) => Some(env),
         Err(e) => {
-            println!("Error decoding base64: {}", e);
-            return None;
+            println!("Unable to use the runcode: {e}");
+            None
         }
     }
 }
 
+/// Asks for the runcode passphrase, unless RUNCODE_PASSPHRASE is set
+fn prompt_passphrase() -> anyhow::Result<String> {
+    if let Ok(passphrase) = std::env::var("RUNCODE_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    Ok(Password::with_theme(&ColorfulTheme::default())
+        .with_prompt("Runcode passphrase")
+        .interact()?)
+}
+
+/// Decodes either runcode format. `passphrase` is only asked for when it's needed.
+pub fn decode_runcode(
+    runcode: &str,
+    passphrase: impl FnOnce() -> anyhow::Result<String>,
+) -> anyhow::Result<Env> {
+    let Some(encoded) = runcode.strip_prefix(RUNCODE_PREFIX) else {
+        log::warn!("Legacy runcodes are deprecated");
+        println!(
+            "Warning: this runcode is not encrypted, and anyone who sees it can read your password. \
+             Run without a runcode to make a new one."
+        );
+        let decoded = general_purpose::STANDARD
+            .decode(runcode)
+            .map_err(|e| anyhow::anyhow!("Error decoding base64: {e}"))?;
+        let decoded_str =
+            String::from_utf8(decoded).map_err(|_| anyhow::anyhow!("Decoded data is not valid UTF-8"))?;
+        return build_env_from_runcode(&decoded_str)
+            .map_err(|e| anyhow::anyhow!("Error parsing JSON into Env: {e}"));
+    };
+
+    let decoded = general_purpose::URL_SAFE_NO_PAD
+        .decode(encoded)
+        .map_err(|_| anyhow::anyhow!("This runcode is damaged, check that it was copied whole"))?;
+    let runcode: RuncodeV2 = serde_json::from_slice(&decoded)
+        .map_err(|_| anyhow::anyhow!("This runcode is damaged, check that it was copied whole"))?;
+
+    let plaintext = match &runcode.key_file {
+        Some(key_file) => {
+            let key = read_key_file(key_file)?;
+            vault::open_with_key(&runcode.sealed, &key).map_err(|_| {
+                anyhow::anyhow!("{key_file:?} is not the key file this runcode was made with")
+            })?
+        }
+        None => vault::open_with_passphrase(&runcode.sealed, &passphrase()?)
+            .map_err(|_| anyhow::anyhow!("Wrong runcode passphrase"))?,
+    };
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
 pub fn build_env_from_runcode(decoded_str: &str) -> Result<Env, serde_json::Error> {
     // Parse the decoded JSON string into an Env struct
     let env: crate::env::Env = serde_json::from_str(decoded_str)?;
     Ok(env)
 }
 
+/// Builds an encrypted `rc2.` runcode
+pub fn encode_runcode(env: &Env, lock: RuncodeLock) -> anyhow::Result<String> {
+    let json = serde_json::to_vec(env)?;
+    let runcode = match lock {
+        RuncodeLock::Passphrase(passphrase) => RuncodeV2 {
+            key_file: None,
+            sealed: vault::seal_with_passphrase(&json, passphrase)?,
+        },
+        RuncodeLock::KeyFile(key_file) => RuncodeV2 {
+            key_file: Some(std::path::absolute(key_file)?),
+            sealed: vault::seal_with_key(&json, &read_or_create_key_file(key_file)?)?,
+        },
+    };
+    Ok(format!(
+        "{RUNCODE_PREFIX}{}",
+        general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_vec(&runcode)?)
+    ))
+}
+
+fn read_key_file(path: &Path) -> anyhow::Result<[u8; 32]> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Unable to read the runcode key file {path:?}: {e}"))?;
+    general_purpose::STANDARD
+        .decode(contents.trim())
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("The runcode key file {path:?} is damaged"))
+}
+
+/// Only the user who made the key file can read it. If another run creates it
+/// first, that run's key is used.
+fn read_or_create_key_file(path: &Path) -> anyhow::Result<[u8; 32]> {
+    if path.exists() {
+        return read_key_file(path);
+    }
+    let mut key = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut key);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = match options.open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return read_key_file(path),
+        Err(e) => return Err(e.into()),
+    };
+    file.write_all(general_purpose::STANDARD.encode(key).as_bytes())?;
+    Ok(key)
+}
+
 pub fn build_base64_runcode_from_env(env: &Env) -> Option<String> {
-    let selections = &["Yes", "No"];
-    match Select::with_theme(&ColorfulTheme::default())
+    let selections = &[
+        "Yes, locked with a passphrase",
+        "Yes, locked with a key file on this computer",
+        "No",
+    ];
+    let lock = Select::with_theme(&ColorfulTheme::default())
         .with_prompt("Do you want your configuration as a runcode?")
-        .default(1)
+        .default(2)
         .items(selections)
         .interact()
-        .unwrap()
-    {
-        0usize => {
-            Some(match serde_json::to_string(env) {
-                Ok(json_string) => {
-                    // Step 2: Encode the JSON string into Base64
-                    let base64_string = general_purpose::STANDARD.encode(json_string);
-                    base64_string
-                }
-                Err(e) => {
-                    println!("Error serializing Env struct: {}", e);
-                    String::new() // Return an empty string if there was an error
-                }
-            })
+        .unwrap();
+
+    let runcode = match lock {
+        0usize => Password::with_theme(&ColorfulTheme::default())
+            .with_prompt("Choose a runcode passphrase")
+            .with_confirmation("Repeat passphrase", "Error: the passphrases don't match.")
+            .interact()
+            .map_err(anyhow::Error::from)
+            .and_then(|passphrase| encode_runcode(env, RuncodeLock::Passphrase(&passphrase))),
+        1usize => {
+            let key_file = Path::new(&env.working_path).join(KEY_FILE);
+            encode_runcode(env, RuncodeLock::KeyFile(&key_file))
+        }
+        _ => return None,
+    };
+
+    match runcode {
+        Ok(runcode) => Some(runcode),
+        Err(e) => {
+            println!("Error building the runcode: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env() -> Env {
+        Env {
+            church_username: "elder".to_string(),
+            church_password: "hunter2".to_string(),
+            working_path: "/tmp/rm".to_string(),
+            ..Default::default()
         }
-        1usize => None,
-        _ => None,
     }
 
-    // Step 1: Serialize the Env struct into a JSON string
+    #[test]
+    fn passphrase_runcode_round_trips() {
+        let runcode = encode_runcode(&env(), RuncodeLock::Passphrase("pass")).unwrap();
+        assert!(runcode.starts_with(RUNCODE_PREFIX));
+        assert!(!runcode.contains("hunter2"));
+
+        let decoded = decode_runcode(&runcode, || Ok("pass".to_string())).unwrap();
+        assert_eq!(decoded.church_password, "hunter2");
+
+        let err = decode_runcode(&runcode, || Ok("wrong".to_string())).unwrap_err();
+        assert_eq!(err.to_string(), "Wrong runcode passphrase");
+    }
+
+    #[test]
+    fn key_file_runcode_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join(KEY_FILE);
+        let runcode = encode_runcode(&env(), RuncodeLock::KeyFile(&key_file)).unwrap();
+        assert!(key_file.exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&key_file).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        let decoded = decode_runcode(&runcode, || panic!("no passphrase needed")).unwrap();
+        assert_eq!(decoded.church_username, "elder");
+
+        // A different key at the same path can't open it
+        std::fs::remove_file(&key_file).unwrap();
+        read_or_create_key_file(&key_file).unwrap();
+        assert!(decode_runcode(&runcode, || panic!("no passphrase needed")).is_err());
+    }
+
+    #[test]
+    fn legacy_runcodes_still_work() {
+        let legacy = general_purpose::STANDARD.encode(serde_json::to_string(&env()).unwrap());
+        let decoded = decode_runcode(&legacy, || panic!("no passphrase needed")).unwrap();
+        assert_eq!(decoded.church_password, "hunter2");
+    }
+
+    #[test]
+    fn damaged_runcodes_are_reported() {
+        let err = decode_runcode("rc2.!!!", || Ok(String::new())).unwrap_err();
+        assert!(err.to_string().contains("damaged"));
+    }
 }
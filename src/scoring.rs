@@ -0,0 +1,555 @@
+// How a referral is scored from their timeline. Nothing here does I/O, so every
+// rule can be tested on its own.
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    calendar::WorkingHours,
+    persons::{ReferralStatus, TimelineEvent, TimelineItemType},
+};
+
+/// The mission's contact standard. The defaults are the standard this program
+/// has always scored against.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScoringRules {
+    /// The mission's time zone, which days start and end in
+    pub time_zone: Tz,
+    /// Days scored, starting on the referral day
+    pub window_days: u32,
+    /// Only people assigned within this many days are scored
+    pub lookback_days: i64,
+    /// Days are scored up to this many days before the run. 1 scores through yesterday.
+    pub cutoff_days: i64,
+    /// Timeline events that count as contacting someone
+    pub contact_types: Vec<TimelineItemType>,
+    /// Whether a day with only unsuccessful attempts counts as a contacted day
+    pub count_failed_attempts: bool,
+    /// When set, days off without a contact are left out of the window and
+    /// contact times are also measured in working minutes
+    pub working_hours: Option<WorkingHours>,
+}
+
+impl Default for ScoringRules {
+    fn default() -> Self {
+        Self {
+            time_zone: chrono_tz::America::New_York,
+            window_days: 7,
+            lookback_days: 8,
+            cutoff_days: 1,
+            contact_types: vec![TimelineItemType::Contact, TimelineItemType::Teaching],
+            count_failed_attempts: true,
+            working_hours: None,
+        }
+    }
+}
+
+impl ScoringRules {
+    fn is_contact(&self, event: &TimelineEvent) -> bool {
+        self.contact_types.contains(&event.item_type)
+    }
+
+    /// The mission's date right now
+    pub fn today(&self) -> NaiveDate {
+        self.day_of(Utc::now())
+    }
+
+    fn day_of(&self, at: DateTime<Utc>) -> NaiveDate {
+        at.with_timezone(&self.time_zone).date_naive()
+    }
+
+    fn works_on(&self, day: NaiveDate) -> bool {
+        self.working_hours.as_ref().is_none_or(|hours| hours.hours_on(day).is_some())
+    }
+}
+
+/// How contacting a referral went within the window
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Outcome {
+    /// No one has tried to contact them since they were referred
+    NotContacted,
+    NotAttempted,
+    /// Tried, but haven't reached them yet
+    Unsuccessful,
+    Successful,
+}
+
+impl Outcome {
+    /// What the endpoint shows
+    pub fn label(&self) -> &'static str {
+        match self {
+            Outcome::NotContacted => "Not Contacted",
+            Outcome::NotAttempted => "Not Attempted",
+            Outcome::Unsuccessful => "Unsuccessful",
+            Outcome::Successful => "Successful",
+        }
+    }
+
+    /// Referral manager's number for the same status. It has no separate
+    /// status for not contacted, so that's not attempted too.
+    pub fn code(&self) -> u8 {
+        ReferralStatus::from(*self) as u8
+    }
+}
+
+impl From<Outcome> for ReferralStatus {
+    fn from(outcome: Outcome) -> Self {
+        match outcome {
+            Outcome::NotContacted | Outcome::NotAttempted => ReferralStatus::NotAttempted,
+            Outcome::Unsuccessful => ReferralStatus::NotSuccessful,
+            Outcome::Successful => ReferralStatus::Successful,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReferralScore {
+    /// Day of the episode's new referral, which the window starts on
+    pub referred_on: NaiveDate,
+    /// Days in the window with a contact attempt, up to and including the successful one
+    pub contacted_days: u32,
+    /// Days of the window that have been scored so far
+    pub window_days: u32,
+    pub outcome: Outcome,
+    pub contact: ContactMetrics,
+    /// The episode's events that were scored, see [`scored_events`]
+    pub events: Vec<TimelineEvent>,
+}
+
+/// How quickly a referral was tried and reached after their latest new referral
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContactMetrics {
+    /// Minutes to the first contact, whether or not it succeeded
+    pub first_attempt_minutes: Option<usize>,
+    /// Minutes to the first successful contact
+    pub first_success_minutes: Option<usize>,
+    /// Contacts before the first success, or all of them if there wasn't one
+    pub attempts: u32,
+    /// The same times counting only working hours, when working hours are set
+    pub first_attempt_working_minutes: Option<usize>,
+    pub first_success_working_minutes: Option<usize>,
+}
+
+impl ReferralScore {
+    /// `contacted/window`, the way the sheet shows it
+    pub fn fraction(&self) -> String {
+        format!("{}/{}", self.contacted_days, self.window_days)
+    }
+}
+
+/// What happened on one day of the window
+#[derive(Debug, PartialEq)]
+enum Day {
+    NoAttempt,
+    Attempted,
+    Reached,
+}
+
+/// The events scoring looks at: new referrals, and contacts with a result
+pub fn scored_events(timeline: &[TimelineEvent], rules: &ScoringRules) -> Vec<TimelineEvent> {
+    timeline
+        .iter()
+        .filter(|event| {
+            event.item_type == TimelineItemType::NewReferral || (rules.is_contact(event) && event.status.is_some())
+        })
+        .cloned()
+        .collect()
+}
+
+/// Scores each new referral in `timeline` on its own, oldest first. An episode
+/// is a new referral and the events up to the next one, and its window ends
+/// the day before the next one arrives. Days are scored up to
+/// `rules.cutoff_days` before `as_of`, so by default today's contacts count
+/// tomorrow. `timeline` is newest first, the way referral manager sends it.
+/// Without a new referral event there's nothing to score from, so it's empty.
+pub fn score_episodes(
+    timeline: &[TimelineEvent],
+    as_of: NaiveDate,
+    rules: &ScoringRules,
+) -> Vec<ReferralScore> {
+    let referrals: Vec<usize> = timeline
+        .iter()
+        .enumerate()
+        .filter(|(_, event)| event.item_type == TimelineItemType::NewReferral)
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut scores = Vec::with_capacity(referrals.len());
+    let mut newer: Option<usize> = None;
+    for &i in &referrals {
+        let events = &timeline[newer.map_or(0, |n| n + 1)..=i];
+        let next_referral = newer.map(|n| rules.day_of(timeline[n].item_date));
+        scores.push(score_episode(rules.day_of(timeline[i].item_date), events, next_referral, as_of, rules));
+        newer = Some(i);
+    }
+    scores.reverse();
+    scores
+}
+
+fn score_episode(
+    referred_on: NaiveDate,
+    timeline: &[TimelineEvent],
+    next_referral: Option<NaiveDate>,
+    as_of: NaiveDate,
+    rules: &ScoringRules,
+) -> ReferralScore {
+    let events = scored_events(timeline, rules);
+    let mut last_day = as_of - Duration::days(rules.cutoff_days);
+    if let Some(next) = next_referral {
+        // A referral the same day still gets that day
+        last_day = last_day.min((next - Duration::days(1)).max(referred_on));
+    }
+    let mut day = referred_on;
+    let mut contacted_days = 0;
+    let mut window_days = 0;
+    let mut outcome = Outcome::NotAttempted;
+
+    while day <= last_day && window_days < rules.window_days {
+        let checked = check_day(day, &events, rules);
+        // Days off only count when they were used anyway
+        let day_off = checked == Day::NoAttempt && !rules.works_on(day);
+        day += Duration::days(1);
+        if day_off {
+            continue;
+        }
+        window_days += 1;
+        match checked {
+            Day::Reached => {
+                contacted_days += 1;
+                outcome = Outcome::Successful;
+                break;
+            }
+            Day::Attempted => {
+                if rules.count_failed_attempts {
+                    contacted_days += 1;
+                }
+                outcome = Outcome::Unsuccessful;
+            }
+            Day::NoAttempt => {}
+        }
+    }
+
+    ReferralScore {
+        referred_on,
+        contacted_days,
+        window_days,
+        outcome,
+        contact: contact_metrics(timeline, rules),
+        events,
+    }
+}
+
+fn check_day(day: NaiveDate, events: &[TimelineEvent], rules: &ScoringRules) -> Day {
+    let attempts: Vec<&TimelineEvent> = events
+        .iter()
+        .filter(|event| rules.day_of(event.item_date) == day && rules.is_contact(event))
+        .collect();
+    if attempts.is_empty() {
+        Day::NoAttempt
+    } else if attempts.iter().any(|event| event.status.unwrap_or(false)) {
+        Day::Reached
+    } else {
+        Day::Attempted
+    }
+}
+
+/// Contacts with a result after the last new referral, the same ones the score
+/// counts. Empty when there's no new referral.
+pub fn contact_metrics(timeline: &[TimelineEvent], rules: &ScoringRules) -> ContactMetrics {
+    let mut metrics = ContactMetrics::default();
+    let Some(latest) = timeline.iter().position(|e| e.item_type == TimelineItemType::NewReferral) else {
+        return metrics;
+    };
+    let referred_at = timeline[latest].item_date;
+
+    let working = |event: &TimelineEvent| {
+        let hours = rules.working_hours.as_ref()?;
+        let tz = rules.time_zone;
+        Some(hours.working_minutes(referred_at.with_timezone(&tz), event.item_date.with_timezone(&tz)))
+    };
+    for event in timeline[..latest].iter().rev().filter(|e| rules.is_contact(e) && e.status.is_some()) {
+        // A contact dated before the referral counts as immediate
+        let minutes = event.item_date.signed_duration_since(referred_at).num_minutes().max(0) as usize;
+        if metrics.first_attempt_minutes.is_none() {
+            metrics.first_attempt_minutes = Some(minutes);
+            metrics.first_attempt_working_minutes = working(event);
+        }
+        if event.status == Some(true) {
+            metrics.first_success_minutes = Some(minutes);
+            metrics.first_success_working_minutes = working(event);
+            break;
+        }
+        metrics.attempts += 1;
+    }
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDateTime, TimeZone};
+
+    /// Mission time, in the default time zone
+    fn at(date: &str) -> DateTime<Utc> {
+        let local = NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap();
+        chrono_tz::America::New_York.from_local_datetime(&local).unwrap().to_utc()
+    }
+
+    fn day(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap()
+    }
+
+    fn event(item_type: TimelineItemType, date: &str, status: Option<bool>) -> TimelineEvent {
+        TimelineEvent {
+            item_type,
+            item_date: at(date),
+            status,
+        }
+    }
+
+    fn referral(date: &str) -> TimelineEvent {
+        event(TimelineItemType::NewReferral, date, None)
+    }
+
+    fn contact(date: &str, reached: bool) -> TimelineEvent {
+        event(TimelineItemType::Contact, date, Some(reached))
+    }
+
+    /// The latest episode
+    fn score(timeline: &[TimelineEvent], as_of: NaiveDate, rules: &ScoringRules) -> ReferralScore {
+        score_episodes(timeline, as_of, rules).pop().unwrap()
+    }
+
+    /// Timelines come newest first
+    fn newest_first(mut events: Vec<TimelineEvent>) -> Vec<TimelineEvent> {
+        events.sort_by_key(|e| std::cmp::Reverse(e.item_date));
+        events
+    }
+
+    #[test]
+    fn same_day_success() {
+        let timeline = newest_first(vec![referral("2024-03-01 09:00"), contact("2024-03-01 10:30", true)]);
+        let score = score(&timeline, day("2024-03-05"), &ScoringRules::default());
+        assert_eq!(score.outcome, Outcome::Successful);
+        assert_eq!(score.fraction(), "1/1");
+        assert_eq!(score.contact.first_attempt_minutes, Some(90));
+    }
+
+    #[test]
+    fn nothing_to_score_without_a_referral() {
+        let rules = ScoringRules::default();
+        assert!(score_episodes(&[], day("2024-03-04"), &rules).is_empty());
+        let timeline = vec![contact("2024-03-01 12:00", true)];
+        assert!(score_episodes(&timeline, day("2024-03-04"), &rules).is_empty());
+    }
+
+    #[test]
+    fn assigned_today_is_not_scored_yet() {
+        let timeline = newest_first(vec![referral("2024-03-05 08:00"), contact("2024-03-05 09:00", true)]);
+        let score = score(&timeline, day("2024-03-05"), &ScoringRules::default());
+        assert_eq!(score.outcome, Outcome::NotAttempted);
+        assert_eq!(score.fraction(), "0/0");
+        assert_eq!(score.contact.first_attempt_minutes, Some(60));
+    }
+
+    #[test]
+    fn attempts_then_success() {
+        let timeline = newest_first(vec![
+            referral("2024-03-01 09:00"),
+            contact("2024-03-01 12:00", false),
+            contact("2024-03-03 12:00", false),
+            contact("2024-03-04 08:00", false),
+            contact("2024-03-04 18:00", true),
+            contact("2024-03-05 12:00", true),
+        ]);
+        let score = score(&timeline, day("2024-03-10"), &ScoringRules::default());
+        assert_eq!(score.outcome, Outcome::Successful);
+        // Day 2 had no attempt, and scoring stops at the first success
+        assert_eq!(score.fraction(), "3/4");
+    }
+
+    #[test]
+    fn unsuccessful_attempts_fill_the_window() {
+        let timeline = newest_first(vec![referral("2024-03-01 09:00"), contact("2024-03-02 12:00", false)]);
+        let score = score(&timeline, day("2024-03-20"), &ScoringRules::default());
+        assert_eq!(score.outcome, Outcome::Unsuccessful);
+        assert_eq!(score.fraction(), "1/7");
+    }
+
+    #[test]
+    fn latest_referral_starts_the_window() {
+        let timeline = newest_first(vec![
+            referral("2024-02-01 09:00"),
+            contact("2024-02-01 10:00", true),
+            referral("2024-03-01 09:00"),
+        ]);
+        let score = score(&timeline, day("2024-03-03"), &ScoringRules::default());
+        assert_eq!(score.referred_on, day("2024-03-01"));
+        assert_eq!(score.outcome, Outcome::NotAttempted);
+        assert_eq!(score.fraction(), "0/2");
+        assert_eq!(score.contact.first_attempt_minutes, None);
+    }
+
+    #[test]
+    fn contacts_without_a_result_are_ignored() {
+        let timeline = newest_first(vec![
+            referral("2024-03-01 09:00"),
+            event(TimelineItemType::Contact, "2024-03-01 12:00", None),
+            event(TimelineItemType::Note, "2024-03-01 13:00", Some(true)),
+            event(TimelineItemType::Teaching, "2024-03-02 12:00", Some(true)),
+        ]);
+        let score = score(&timeline, day("2024-03-05"), &ScoringRules::default());
+        assert_eq!(score.outcome, Outcome::Successful);
+        assert_eq!(score.fraction(), "1/2");
+        assert_eq!(scored_events(&timeline, &ScoringRules::default()).len(), 2);
+        // and aren't attempts either
+        assert_eq!((score.contact.first_attempt_minutes, score.contact.attempts), (Some(27 * 60), 0));
+    }
+
+    #[test]
+    fn rules_change_the_window_and_what_counts() {
+        let timeline = newest_first(vec![
+            referral("2024-03-01 09:00"),
+            contact("2024-03-01 12:00", false),
+            event(TimelineItemType::Teaching, "2024-03-02 12:00", Some(true)),
+            contact("2024-03-03 12:00", true),
+        ]);
+
+        let short = ScoringRules {
+            window_days: 1,
+            ..Default::default()
+        };
+        let score = score(&timeline, day("2024-03-10"), &short);
+        assert_eq!((score.outcome, score.fraction()), (Outcome::Unsuccessful, "1/1".to_string()));
+
+        let contacts_only = ScoringRules {
+            contact_types: vec![TimelineItemType::Contact],
+            count_failed_attempts: false,
+            ..Default::default()
+        };
+        let score = self::score(&timeline, day("2024-03-10"), &contacts_only);
+        assert_eq!((score.outcome, score.fraction()), (Outcome::Successful, "1/3".to_string()));
+
+        let same_day = ScoringRules {
+            cutoff_days: 0,
+            ..Default::default()
+        };
+        let score = self::score(&timeline, day("2024-03-02"), &same_day);
+        assert_eq!((score.outcome, score.fraction()), (Outcome::Successful, "2/2".to_string()));
+    }
+
+    #[test]
+    fn first_attempt_and_first_success_are_separate() {
+        let timeline = newest_first(vec![
+            referral("2024-03-01 09:00"),
+            contact("2024-03-01 10:00", false),
+            contact("2024-03-02 09:00", false),
+            contact("2024-03-02 13:00", true),
+            contact("2024-03-03 09:00", false),
+        ]);
+        let metrics = contact_metrics(&timeline, &ScoringRules::default());
+        assert_eq!(
+            metrics,
+            ContactMetrics {
+                first_attempt_minutes: Some(60),
+                first_success_minutes: Some(28 * 60),
+                attempts: 2,
+                ..Default::default()
+            }
+        );
+
+        let never_reached = newest_first(vec![referral("2024-03-01 09:00"), contact("2024-03-01 09:30", false)]);
+        let metrics = contact_metrics(&never_reached, &ScoringRules::default());
+        assert_eq!((metrics.first_attempt_minutes, metrics.first_success_minutes, metrics.attempts), (Some(30), None, 1));
+    }
+
+    #[test]
+    fn contacts_dated_before_the_referral_are_immediate() {
+        // Listed as newer than the referral, but stamped an hour before it
+        let timeline = vec![contact("2024-03-01 08:00", true), referral("2024-03-01 09:00")];
+        let metrics = contact_metrics(&timeline, &ScoringRules::default());
+        assert_eq!((metrics.first_attempt_minutes, metrics.first_success_minutes), (Some(0), Some(0)));
+    }
+
+    #[test]
+    fn working_hours_skip_days_off_and_nights() {
+        let rules = ScoringRules {
+            working_hours: Some(
+                serde_json::from_value(serde_json::json!({
+                    "start": "09:00:00",
+                    "end": "21:30:00",
+                    "weekdays": { "Sun": null },
+                }))
+                .unwrap(),
+            ),
+            ..Default::default()
+        };
+        // Saturday night, then Monday morning
+        let timeline = newest_first(vec![referral("2024-03-02 21:30"), contact("2024-03-04 09:30", true)]);
+        let score = score(&timeline, day("2024-03-10"), &rules);
+        assert_eq!(score.fraction(), "1/2");
+        assert_eq!(score.contact.first_attempt_minutes, Some(36 * 60));
+        assert_eq!(score.contact.first_attempt_working_minutes, Some(30));
+        assert_eq!(score.contact.first_success_working_minutes, Some(30));
+    }
+
+    #[test]
+    fn days_follow_the_mission_time_zone() {
+        let denver = |date: &str| {
+            let local = NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap();
+            chrono_tz::America::Denver.from_local_datetime(&local).unwrap().to_utc()
+        };
+        // An evening contact is the next day in UTC
+        let timeline = vec![
+            TimelineEvent {
+                item_type: TimelineItemType::Contact,
+                item_date: denver("2024-03-01 20:00"),
+                status: Some(true),
+            },
+            TimelineEvent {
+                item_type: TimelineItemType::NewReferral,
+                item_date: denver("2024-03-01 16:00"),
+                status: None,
+            },
+        ];
+        let rules = ScoringRules {
+            time_zone: chrono_tz::America::Denver,
+            ..Default::default()
+        };
+        let score = score(&timeline, day("2024-03-05"), &rules);
+        assert_eq!(score.referred_on, day("2024-03-01"));
+        assert_eq!(score.fraction(), "1/1");
+
+        let utc = ScoringRules {
+            time_zone: chrono_tz::UTC,
+            ..Default::default()
+        };
+        assert_eq!(self::score(&timeline, day("2024-03-05"), &utc).fraction(), "1/2");
+    }
+
+    #[test]
+    fn each_referral_is_its_own_episode() {
+        let timeline = newest_first(vec![
+            referral("2024-03-01 09:00"),
+            contact("2024-03-01 10:00", false),
+            contact("2024-03-02 10:00", false),
+            referral("2024-03-04 09:00"),
+            contact("2024-03-05 09:00", true),
+        ]);
+        let episodes = score_episodes(&timeline, day("2024-03-20"), &ScoringRules::default());
+        assert_eq!(episodes.len(), 2);
+
+        // The first episode stops the day before it was referred again
+        assert_eq!(episodes[0].referred_on, day("2024-03-01"));
+        assert_eq!((episodes[0].outcome, episodes[0].fraction()), (Outcome::Unsuccessful, "2/3".to_string()));
+        assert_eq!((episodes[0].contact.first_attempt_minutes, episodes[0].contact.attempts), (Some(60), 2));
+        assert_eq!(episodes[0].events.len(), 3);
+
+        assert_eq!(episodes[1].referred_on, day("2024-03-04"));
+        assert_eq!((episodes[1].outcome, episodes[1].fraction()), (Outcome::Successful, "1/2".to_string()));
+        assert_eq!(episodes[1].contact.first_success_minutes, Some(24 * 60));
+        assert_eq!(score(&timeline, day("2024-03-20"), &ScoringRules::default()), episodes[1]);
+    }
+}
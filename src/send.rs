@@ -1,25 +1,621 @@
-//Karter Arritt
-use reqwest::Client;
-use serde_json::Value;
-
-pub async fn send_to_google_apps_script(
-    body: Value,
-    endpoint_url: String
-) -> Result<String, Box<dyn std::error::Error>> {
-    let client = Client::new();
-
-    // Append static query parameters to the endpoint URL
-    let endpoint_url_with_params = format!("{}?location=ReferralScore&operation=replace", endpoint_url);
-    
-    // Send POST request
-    let res = client.post(endpoint_url_with_params).json(&body).send().await?;
-
-    // Check for successful response
-    if res.status().is_success() {
-        // Parse the response JSON (assuming it's a decrypted object)
-        let response_text = res.text().await?;
-        Ok(response_text)
-    } else {
-        Err(format!("Request failed with status: {}", res.status()).into())
-    }
-}
+//Karter Arritt
+use std::fmt::Write;
+
+use base64::{engine::general_purpose, Engine};
+use chacha20poly1305::{
+    aead::{self, Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use log::warn;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::{
+    diff::SnapshotDiff,
+    error::{Error, Result},
+    persons::GASPerson,
+    report::Aggregates,
+    trends::Trends,
+    scoring::Outcome,
+};
+
+/// Bytes that JavaScript's `String.prototype.trim` strips, limited to the single
+/// byte range `atob` in the Apps Script handler produces.
+const JS_TRIM_BYTES: [u8; 7] = [0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x20, 0xa0];
+
+/// Whitespace JSON.parse ignores, so it is safe to pad the plaintext with.
+const JSON_WHITESPACE: [u8; 4] = [b' ', b'\t', b'\n', b'\r'];
+
+/// Current version of the authenticated envelope
+pub const ENVELOPE_VERSION: u8 = 2;
+
+const KDF_SALT: &[u8] = b"referral_list_endpoint";
+const TAG_LEN: usize = 16;
+
+/// Which envelope the endpoint expects
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvelopeFormat {
+    /// `{ "body": <base64 XOR> }`, what the shipped `AppsScriptPostHandler.js` decrypts
+    #[default]
+    Legacy,
+    /// Authenticated XChaCha20-Poly1305 envelope, see [`seal_envelope`]
+    V2,
+}
+
+impl std::str::FromStr for EnvelopeFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "legacy" | "v1" => Ok(Self::Legacy),
+            "v2" => Ok(Self::V2),
+            _ => Err(anyhow::anyhow!("Unknown envelope format {s:?}, expected legacy or v2")),
+        }
+    }
+}
+
+/// Optional sections sent along with the referral rows
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PayloadSections {
+    /// Changes between the two newest people list snapshots
+    pub diff: bool,
+    /// Statistics for each area, district and zone
+    pub aggregates: bool,
+    /// Each area and zone compared with previous weeks, from the history
+    pub trends: bool,
+}
+
+/// How outcomes are written in the referral rows
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutcomeStyle {
+    /// The labels in [`OutcomeFormat`]
+    #[default]
+    Labels,
+    /// Referral manager's status numbers, see [`Outcome::code`]
+    Codes,
+}
+
+/// The mapping from outcomes to what the sheet shows. Change the labels to
+/// localize them, or send codes so renaming a label can't break formulas.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutcomeFormat {
+    pub style: OutcomeStyle,
+    pub not_contacted: String,
+    pub not_attempted: String,
+    pub unsuccessful: String,
+    pub successful: String,
+}
+
+impl Default for OutcomeFormat {
+    fn default() -> Self {
+        Self {
+            style: OutcomeStyle::default(),
+            not_contacted: Outcome::NotContacted.label().to_string(),
+            not_attempted: Outcome::NotAttempted.label().to_string(),
+            unsuccessful: Outcome::Unsuccessful.label().to_string(),
+            successful: Outcome::Successful.label().to_string(),
+        }
+    }
+}
+
+impl OutcomeFormat {
+    pub fn value(&self, outcome: Outcome) -> OutcomeValue {
+        if self.style == OutcomeStyle::Codes {
+            return OutcomeValue::Code(outcome.code());
+        }
+        let label = match outcome {
+            Outcome::NotContacted => &self.not_contacted,
+            Outcome::NotAttempted => &self.not_attempted,
+            Outcome::Unsuccessful => &self.unsuccessful,
+            Outcome::Successful => &self.successful,
+        };
+        OutcomeValue::Label(label.clone())
+    }
+}
+
+/// An outcome the way the endpoint receives it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum OutcomeValue {
+    Code(u8),
+    Label(String),
+}
+
+/// What's sent to the endpoint
+#[derive(Debug, Default, Serialize)]
+pub struct Payload {
+    pub referrals: Vec<GASPerson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<SnapshotDiff>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aggregates: Option<Aggregates>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trends: Option<Trends>,
+}
+
+impl Payload {
+    /// Just the rows when there are no extra sections, which is all older handlers understand
+    pub fn to_value(&self) -> serde_json::Result<Value> {
+        if self.diff.is_none() && self.aggregates.is_none() && self.trends.is_none() {
+            return serde_json::to_value(&self.referrals);
+        }
+        serde_json::to_value(self)
+    }
+}
+
+/// Version 2 wire format. Binary fields are standard base64.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EnvelopeV2 {
+    pub v: u8,
+    pub kid: String,
+    pub nonce: String,
+    pub ciphertext: String,
+    pub tag: String,
+}
+
+pub async fn send_to_google_apps_script(
+    body: Value,
+    endpoint_url: String,
+    crypt_key: &str,
+    format: EnvelopeFormat,
+) -> Result<String> {
+    let client = Client::new();
+    let envelope = match format {
+        EnvelopeFormat::Legacy => build_envelope(&body, crypt_key)?,
+        EnvelopeFormat::V2 => serde_json::to_value(seal_envelope(&body, crypt_key)?).map_err(anyhow::Error::from)?,
+    };
+
+    // Append static query parameters to the endpoint URL
+    let endpoint_url_with_params = format!("{}?location=ReferralScore&operation=replace", endpoint_url);
+
+    // Send POST request
+    let res = client.post(endpoint_url_with_params).json(&envelope).send().await?;
+
+    // Check for successful response
+    let status = res.status();
+    if status.is_success() {
+        // Parse the response JSON (assuming it's a decrypted object)
+        let response_text = res.text().await?;
+        Ok(response_text)
+    } else {
+        Err(Error::EndpointRejected {
+            status,
+            body: res.text().await.unwrap_or_default(),
+        })
+    }
+}
+
+/// Wraps the payload in the `{ "body": ... }` envelope `AppsScriptPostHandler.js` reads.
+/// Without a key the JSON goes out as a plain string, which the handler parses as-is.
+pub fn build_envelope(body: &Value, crypt_key: &str) -> anyhow::Result<Value> {
+    let plaintext = to_ascii_json(body)?;
+    if crypt_key.is_empty() {
+        warn!("No CRYPT_KEY set, sending the payload unencrypted");
+        return Ok(json!({ "body": plaintext }));
+    }
+    Ok(json!({ "body": encrypt_with_otp(&plaintext, crypt_key)? }))
+}
+
+/// Serializes to JSON with every non-ASCII character escaped as `\uXXXX`.
+/// The handler XORs single bytes back into UTF-16 code units, so raw UTF-8
+/// would come out as mojibake on the sheet.
+fn to_ascii_json(body: &Value) -> serde_json::Result<String> {
+    let json = serde_json::to_string(body)?;
+    let mut out = String::with_capacity(json.len());
+    let mut units = [0u16; 2];
+    for c in json.chars() {
+        if c.is_ascii() {
+            out.push(c);
+        } else {
+            for unit in c.encode_utf16(&mut units) {
+                // Writing to a String can't fail
+                let _ = write!(out, "\\u{unit:04x}");
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// The inverse of `decryptWithOTP` in `AppsScriptPostHandler.js`.
+///
+/// The handler's `atob` only decodes whole 4 character groups correctly and it
+/// trims the decoded string before XORing, so the plaintext is padded with JSON
+/// whitespace until its length is a multiple of 3 and neither end of the
+/// ciphertext is a byte `trim` would eat.
+pub fn encrypt_with_otp(plaintext: &str, crypt_key: &str) -> anyhow::Result<String> {
+    let key: Vec<u16> = crypt_key.encode_utf16().collect();
+    if key.is_empty() {
+        return Err(anyhow::anyhow!("CRYPT_KEY is empty"));
+    }
+    if key.iter().any(|k| *k > 0xff) {
+        return Err(anyhow::anyhow!("CRYPT_KEY may only contain Latin-1 characters"));
+    }
+    if !plaintext.is_ascii() {
+        return Err(anyhow::anyhow!("Plaintext must be ASCII"));
+    }
+
+    let xor = |data: &[u8]| -> Vec<u8> {
+        data.iter()
+            .enumerate()
+            .map(|(i, b)| b ^ key[i % key.len()] as u8)
+            .collect()
+    };
+
+    let prefixes = std::iter::once(None).chain(JSON_WHITESPACE.iter().copied().map(Some));
+    for prefix in prefixes {
+        let mut padded: Vec<u8> = prefix.into_iter().collect();
+        padded.extend_from_slice(plaintext.as_bytes());
+        let base_len = padded.len();
+
+        for suffix_len in 0..6 {
+            if !(base_len + suffix_len).is_multiple_of(3) {
+                continue;
+            }
+            // Only the last padding byte can end up at the edge of the ciphertext
+            let last_bytes: Vec<Option<u8>> = if suffix_len == 0 {
+                vec![None]
+            } else {
+                JSON_WHITESPACE.iter().copied().map(Some).collect()
+            };
+            for last in last_bytes {
+                let mut candidate = padded.clone();
+                if let Some(last) = last {
+                    candidate.extend(std::iter::repeat_n(b' ', suffix_len - 1));
+                    candidate.push(last);
+                }
+                let encrypted = xor(&candidate);
+                let first_ok = !JS_TRIM_BYTES.contains(&encrypted[0]);
+                let last_ok = !JS_TRIM_BYTES.contains(&encrypted[encrypted.len() - 1]);
+                if first_ok && last_ok {
+                    return Ok(general_purpose::STANDARD.encode(encrypted));
+                }
+            }
+        }
+    }
+    Err(anyhow::anyhow!("Unable to pad the payload for this CRYPT_KEY"))
+}
+
+/// Key material for the v2 envelope, derived from the shared CRYPT_KEY with HKDF-SHA256
+struct EnvelopeKey {
+    key: [u8; 32],
+    kid: String,
+}
+
+impl EnvelopeKey {
+    fn derive(crypt_key: &str) -> anyhow::Result<Self> {
+        if crypt_key.is_empty() {
+            return Err(anyhow::anyhow!("CRYPT_KEY is empty"));
+        }
+        let hkdf = Hkdf::<Sha256>::new(Some(KDF_SALT), crypt_key.as_bytes());
+        let mut key = [0u8; 32];
+        let mut kid = [0u8; 8];
+        hkdf.expand(b"envelope v2 key", &mut key)
+            .map_err(|_| anyhow::anyhow!("Unable to derive the envelope key"))?;
+        hkdf.expand(b"envelope v2 kid", &mut kid)
+            .map_err(|_| anyhow::anyhow!("Unable to derive the envelope key id"))?;
+        Ok(Self {
+            key,
+            kid: kid.iter().map(|b| format!("{b:02x}")).collect(),
+        })
+    }
+
+    /// The version and key id are authenticated along with the ciphertext
+    fn aad(&self, version: u8) -> Vec<u8> {
+        format!("v{version}.{}", self.kid).into_bytes()
+    }
+}
+
+/// Encrypts and authenticates the payload under a key derived from CRYPT_KEY
+pub fn seal_envelope(body: &Value, crypt_key: &str) -> anyhow::Result<EnvelopeV2> {
+    let key = EnvelopeKey::derive(crypt_key)?;
+    let cipher = XChaCha20Poly1305::new((&key.key).into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let plaintext = serde_json::to_vec(body)?;
+    let aad = key.aad(ENVELOPE_VERSION);
+
+    let mut sealed = cipher
+        .encrypt(&nonce, aead::Payload { msg: &plaintext, aad: &aad })
+        .map_err(|_| anyhow::anyhow!("Encrypting the payload failed"))?;
+    let tag = sealed.split_off(sealed.len() - TAG_LEN);
+
+    Ok(EnvelopeV2 {
+        v: ENVELOPE_VERSION,
+        kid: key.kid,
+        nonce: general_purpose::STANDARD.encode(nonce),
+        ciphertext: general_purpose::STANDARD.encode(sealed),
+        tag: general_purpose::STANDARD.encode(tag),
+    })
+}
+
+/// Verifies and decrypts a posted v2 envelope. Anything forged, corrupted,
+/// sealed under another key or in an unknown version is rejected.
+pub fn open_envelope(envelope: &Value, crypt_key: &str) -> anyhow::Result<Value> {
+    let envelope: EnvelopeV2 = serde_json::from_value(envelope.clone())
+        .map_err(|e| anyhow::anyhow!("Not a v2 envelope: {e}"))?;
+    if envelope.v != ENVELOPE_VERSION {
+        return Err(anyhow::anyhow!("Unsupported envelope version {}", envelope.v));
+    }
+
+    let key = EnvelopeKey::derive(crypt_key)?;
+    if envelope.kid != key.kid {
+        return Err(anyhow::anyhow!("Envelope was sealed with an unknown key id {}", envelope.kid));
+    }
+
+    let decode = |field: &str, value: &str| {
+        general_purpose::STANDARD
+            .decode(value)
+            .map_err(|e| anyhow::anyhow!("Envelope {field} is not valid base64: {e}"))
+    };
+    let nonce = decode("nonce", &envelope.nonce)?;
+    let mut sealed = decode("ciphertext", &envelope.ciphertext)?;
+    let tag = decode("tag", &envelope.tag)?;
+    if nonce.len() != 24 {
+        return Err(anyhow::anyhow!("Envelope nonce has the wrong length"));
+    }
+    if tag.len() != TAG_LEN {
+        return Err(anyhow::anyhow!("Envelope tag has the wrong length"));
+    }
+    sealed.extend_from_slice(&tag);
+
+    let cipher = XChaCha20Poly1305::new((&key.key).into());
+    let aad = key.aad(envelope.v);
+    let plaintext = cipher
+        .decrypt(XNonce::from_slice(&nonce), aead::Payload { msg: &sealed, aad: &aad })
+        .map_err(|_| anyhow::anyhow!("Envelope failed authentication"))?;
+
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Port of the handler's hand-rolled `atob`, quirks included
+    fn js_atob(base64: &str) -> Vec<u16> {
+        const CHARS: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/=";
+        let cleaned: Vec<char> = base64
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '+' || *c == '/')
+            .collect();
+        let mut output = Vec::new();
+        let mut buffer: i32 = 0;
+        for (i, c) in cleaned.iter().enumerate() {
+            let index = CHARS.find(*c).unwrap() as i32;
+            buffer = (buffer << 6) | index;
+            if (i + 1) % 4 == 0 || i == cleaned.len() - 1 {
+                for j in [16, 8, 0] {
+                    output.push(((buffer >> j) & 0xff) as u16);
+                }
+                buffer = 0;
+            }
+        }
+        output
+    }
+
+    /// Port of `decryptWithOTP`, including its retry with a trailing `]`
+    fn js_decrypt_with_otp(encrypted_base64: &str, crypt_key: &str) -> Option<Value> {
+        const JS_WHITESPACE: [u16; 7] = [0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x20, 0xa0];
+        let encrypted = js_atob(encrypted_base64);
+        let start = encrypted.iter().position(|c| !JS_WHITESPACE.contains(c))?;
+        let end = encrypted.iter().rposition(|c| !JS_WHITESPACE.contains(c))?;
+        let encrypted = &encrypted[start..=end];
+
+        let key: Vec<u16> = crypt_key.encode_utf16().collect();
+        let decrypted: Vec<u16> = encrypted
+            .iter()
+            .enumerate()
+            .map(|(i, c)| c ^ key[i % key.len()])
+            .collect();
+        let decrypted = String::from_utf16_lossy(&decrypted);
+        serde_json::from_str(&decrypted)
+            .or_else(|_| serde_json::from_str(&format!("{decrypted}]")))
+            .ok()
+    }
+
+    /// Port of `getDataOut`
+    fn js_get_data_out(envelope: &Value, crypt_key: &str) -> Option<Value> {
+        let body = envelope["body"].as_str()?;
+        if let Ok(v) = serde_json::from_str(body) {
+            return Some(v);
+        }
+        js_decrypt_with_otp(body, crypt_key)
+    }
+
+    fn sample_payload(rows: usize) -> Value {
+        let rows: Vec<Value> = (0..rows)
+            .map(|i| {
+                json!({
+                    "name": format!("Person {i}"),
+                    "contact_time": i as f64 / 7.0,
+                    "score": format!("{}/7", i % 8),
+                    "area": "Riverside",
+                    "referral_status": "Successful",
+                })
+            })
+            .collect();
+        Value::Array(rows)
+    }
+
+    #[test]
+    fn round_trips_through_handler() {
+        let keys = ["k", "key", "SuperSecret123", "!!!!", ")*+,-", "\u{e9}t\u{e9}"];
+        for key in keys {
+            for rows in 0..12 {
+                let payload = sample_payload(rows);
+                let envelope = build_envelope(&payload, key).unwrap();
+                assert_eq!(js_get_data_out(&envelope, key), Some(payload), "key {key:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn every_single_byte_key_round_trips() {
+        let payload = sample_payload(3);
+        for k in 1u8..=255 {
+            let key = (k as char).to_string();
+            let envelope = build_envelope(&payload, &key).unwrap();
+            assert_eq!(js_get_data_out(&envelope, &key), Some(payload.clone()), "key {k:#x}");
+        }
+    }
+
+    #[test]
+    fn non_ascii_names_survive() {
+        let payload = json!([{ "name": "Jos\u{e9} \u{65e5}\u{672c} \u{1f600}", "area": "S\u{e3}o Paulo" }]);
+        let envelope = build_envelope(&payload, "key").unwrap();
+        assert_eq!(js_get_data_out(&envelope, "key"), Some(payload));
+    }
+
+    #[test]
+    fn ciphertext_has_no_base64_padding() {
+        let envelope = build_envelope(&sample_payload(5), "key").unwrap();
+        assert!(!envelope["body"].as_str().unwrap().contains('='));
+    }
+
+    #[test]
+    fn empty_key_sends_plain_json() {
+        let payload = sample_payload(2);
+        let envelope = build_envelope(&payload, "").unwrap();
+        assert_eq!(js_get_data_out(&envelope, ""), Some(payload));
+    }
+
+    #[test]
+    fn rejects_keys_the_handler_cannot_use() {
+        assert!(build_envelope(&sample_payload(1), "\u{263a}").is_err());
+    }
+
+    #[test]
+    fn v2_round_trips() {
+        let payload = json!([{ "name": "Jos\u{e9}", "score": "3/7" }]);
+        let envelope = seal_envelope(&payload, "key").unwrap();
+        let posted = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(posted["v"], 2);
+        assert_eq!(open_envelope(&posted, "key").unwrap(), payload);
+    }
+
+    #[test]
+    fn v2_uses_fresh_nonces() {
+        let payload = sample_payload(2);
+        let a = seal_envelope(&payload, "key").unwrap();
+        let b = seal_envelope(&payload, "key").unwrap();
+        assert_ne!(a.nonce, b.nonce);
+        assert_ne!(a.ciphertext, b.ciphertext);
+        assert_eq!(a.kid, b.kid);
+    }
+
+    #[test]
+    fn v2_rejects_tampering() {
+        let payload = sample_payload(3);
+        let posted = serde_json::to_value(seal_envelope(&payload, "key").unwrap()).unwrap();
+
+        let mut ciphertext = general_purpose::STANDARD
+            .decode(posted["ciphertext"].as_str().unwrap())
+            .unwrap();
+        ciphertext[0] ^= 1;
+        let mut forged = posted.clone();
+        forged["ciphertext"] = json!(general_purpose::STANDARD.encode(ciphertext));
+        assert!(open_envelope(&forged, "key").is_err());
+
+        let mut forged = posted.clone();
+        forged["tag"] = json!(general_purpose::STANDARD.encode([0u8; TAG_LEN]));
+        assert!(open_envelope(&forged, "key").is_err());
+
+        let mut forged = posted.clone();
+        forged["v"] = json!(3);
+        assert!(open_envelope(&forged, "key").is_err());
+
+        let mut forged = posted.clone();
+        forged["nonce"] = json!("not base64!");
+        assert!(open_envelope(&forged, "key").is_err());
+
+        assert!(open_envelope(&json!({ "body": "legacy" }), "key").is_err());
+    }
+
+    #[test]
+    fn v2_rejects_wrong_key() {
+        let posted = serde_json::to_value(seal_envelope(&sample_payload(1), "key").unwrap()).unwrap();
+        assert!(open_envelope(&posted, "other key").is_err());
+
+        // Even when the key id is made to match, the tag still won't verify
+        let mut forged = posted.clone();
+        forged["kid"] = json!(EnvelopeKey::derive("other key").unwrap().kid);
+        assert!(open_envelope(&forged, "other key").is_err());
+    }
+
+    #[test]
+    fn envelope_format_parses() {
+        assert_eq!("v2".parse::<EnvelopeFormat>().unwrap(), EnvelopeFormat::V2);
+        assert_eq!("Legacy".parse::<EnvelopeFormat>().unwrap(), EnvelopeFormat::Legacy);
+        assert!("v9".parse::<EnvelopeFormat>().is_err());
+    }
+
+    #[tokio::test]
+    async fn endpoint_rejection_is_typed() {
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_string("bad input"))
+            .mount(&server)
+            .await;
+
+        let err = send_to_google_apps_script(sample_payload(1), server.uri(), "key", EnvelopeFormat::V2)
+            .await
+            .unwrap_err();
+        match &err {
+            Error::EndpointRejected { status, body } => {
+                assert_eq!(status.as_u16(), 400);
+                assert_eq!(body, "bad input");
+            }
+            other => panic!("expected a rejection, got {other:?}"),
+        }
+        assert_eq!(err.exit_code(), 8);
+    }
+
+    #[test]
+    fn payload_is_plain_rows_without_sections() {
+        let mut payload = Payload::default();
+        assert_eq!(payload.to_value().unwrap(), json!([]));
+
+        payload.diff = Some(SnapshotDiff::default());
+        let value = payload.to_value().unwrap();
+        assert_eq!(value["referrals"], json!([]));
+        assert_eq!(value["diff"]["new_referrals"], json!([]));
+
+        let payload = Payload {
+            aggregates: Some(Aggregates::default()),
+            ..Default::default()
+        };
+        assert_eq!(payload.to_value().unwrap()["aggregates"]["zones"], json!([]));
+    }
+
+    #[test]
+    fn outcomes_follow_the_configured_format() {
+        let format = OutcomeFormat::default();
+        assert_eq!(json!(format.value(Outcome::NotAttempted)), json!("Not Attempted"));
+
+        let localized = OutcomeFormat {
+            successful: "Exitoso".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(json!(localized.value(Outcome::Successful)), json!("Exitoso"));
+
+        let codes = OutcomeFormat {
+            style: OutcomeStyle::Codes,
+            ..Default::default()
+        };
+        let values: Vec<Value> = [Outcome::NotAttempted, Outcome::Unsuccessful, Outcome::Successful]
+            .into_iter()
+            .map(|o| json!(codes.value(o)))
+            .collect();
+        assert_eq!(values, vec![json!(10), json!(20), json!(30)]);
+
+        let parsed: OutcomeFormat = serde_json::from_value(json!({ "style": "codes" })).unwrap();
+        assert_eq!(parsed, codes);
+    }
+}
@@ -0,0 +1,92 @@
+// Tunables read from settings.json in the working path. Every field has a
+// default, so the file only needs the values being changed.
+
+use std::path::{Path, PathBuf};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::CacheSettings,
+    retry::RetryPolicy, scoring::ScoringRules,
+    send::{OutcomeFormat, PayloadSections},
+    trends::TrendSettings,
+};
+
+pub const SETTINGS_FILE: &str = "settings.json";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// How requests to church servers are retried
+    pub retry: RetryPolicy,
+    /// Timelines downloaded at the same time
+    pub concurrency: usize,
+    /// How long a downloaded timeline is reused for. 0 turns the timeline cache off.
+    pub timeline_ttl_minutes: u64,
+    /// People list snapshots
+    pub cache: CacheSettings,
+    /// Extra sections sent to the endpoint
+    pub payload: PayloadSections,
+    /// How outcomes are written in the referral rows
+    pub outcomes: OutcomeFormat,
+    /// Record every run in history.sqlite3
+    pub history: bool,
+    /// What a referral is scored against
+    pub scoring: ScoringRules,
+    /// The windows trends compare
+    pub trends: TrendSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            retry: RetryPolicy::default(),
+            concurrency: 3,
+            timeline_ttl_minutes: 60,
+            cache: CacheSettings::default(),
+            payload: PayloadSections::default(),
+            outcomes: OutcomeFormat::default(),
+            history: true,
+            scoring: ScoringRules::default(),
+            trends: TrendSettings::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn path_in(working_path: &Path) -> PathBuf {
+        working_path.join(SETTINGS_FILE)
+    }
+
+    /// Loads the settings, writing out the defaults the first time so there's a file to edit
+    pub fn load(working_path: &Path) -> anyhow::Result<Self> {
+        let path = Self::path_in(working_path);
+        if !path.exists() {
+            info!("No settings saved, writing the defaults to {path:?}");
+            let settings = Self::default();
+            std::fs::write(&path, serde_json::to_string_pretty(&settings)?)?;
+            return Ok(settings);
+        }
+        let settings = serde_json::from_str(&std::fs::read_to_string(&path)?)
+            .map_err(|e| anyhow::anyhow!("{path:?} is not valid: {e}"))?;
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_defaults_and_reads_partial_files() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(Settings::load(dir.path()).unwrap(), Settings::default());
+        assert!(Settings::path_in(dir.path()).exists());
+
+        std::fs::write(Settings::path_in(dir.path()), r#"{ "retry": { "max_attempts": 7 } }"#).unwrap();
+        let settings = Settings::load(dir.path()).unwrap();
+        assert_eq!(settings.retry.max_attempts, 7);
+        assert_eq!(settings.retry.base_delay_ms, RetryPolicy::default().base_delay_ms);
+    }
+}
@@ -0,0 +1,104 @@
+// People a run couldn't score and why, written to skipped.json in the working path
+
+use std::{collections::BTreeMap, path::Path};
+
+use serde::Serialize;
+
+use crate::{error::Error, persons::Person};
+
+pub const SKIPPED_FILE: &str = "skipped.json";
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "reason", content = "detail", rename_all = "snake_case")]
+pub enum SkipReason {
+    /// Referral manager didn't send their timeline
+    TimelineFetchFailed(String),
+    /// Their timeline didn't look like a timeline
+    ParseError(String),
+    /// No new referral event to score from
+    NoReferralEvent,
+    /// Processing them stopped unexpectedly
+    Crashed(String),
+}
+
+impl SkipReason {
+    pub fn from_timeline_error(error: &Error) -> Self {
+        match error {
+            Error::SchemaDrift(e) => SkipReason::ParseError(e.clone()),
+            e => SkipReason::TimelineFetchFailed(e.to_string()),
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            SkipReason::TimelineFetchFailed(_) => "timeline fetch failed",
+            SkipReason::ParseError(_) => "unreadable timeline",
+            SkipReason::NoReferralEvent => "no referral event",
+            SkipReason::Crashed(_) => "crashed",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Skipped {
+    pub guid: String,
+    pub name: String,
+    pub area: Option<String>,
+    #[serde(flatten)]
+    pub reason: SkipReason,
+}
+
+impl Skipped {
+    pub fn new(person: &Person, reason: SkipReason) -> Self {
+        Self {
+            guid: person.guid.clone(),
+            name: person.first_name.clone(),
+            area: person.area_name.clone(),
+            reason,
+        }
+    }
+}
+
+/// One line for the end of a run, e.g. `Scored 12 referrals, skipped 3 (2 no referral event, 1 crashed)`
+pub fn summary(scored: usize, skipped: &[Skipped]) -> String {
+    if skipped.is_empty() {
+        return format!("Scored {scored} referrals, skipped none");
+    }
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for s in skipped {
+        *counts.entry(s.reason.label()).or_default() += 1;
+    }
+    let counts: Vec<String> = counts.into_iter().map(|(label, n)| format!("{n} {label}")).collect();
+    format!("Scored {scored} referrals, skipped {} ({})", skipped.len(), counts.join(", "))
+}
+
+/// Overwrites skipped.json, so it only ever lists the latest run
+pub fn save(working_path: &Path, skipped: &[Skipped]) -> anyhow::Result<()> {
+    let file = std::fs::File::create(working_path.join(SKIPPED_FILE))?;
+    serde_json::to_writer_pretty(std::io::BufWriter::new(file), skipped)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_and_serializes_reasons() {
+        let person = &Person::parse_lossy(serde_json::json!({ "persons": [
+            crate::mock::person("a", "Alex", "Riverside", chrono::Utc::now()),
+        ]}))[0];
+        let skipped = vec![
+            Skipped::new(person, SkipReason::NoReferralEvent),
+            Skipped::new(person, SkipReason::from_timeline_error(&Error::SchemaDrift("not a list".into()))),
+            Skipped::new(person, SkipReason::NoReferralEvent),
+        ];
+        assert_eq!(summary(4, &[]), "Scored 4 referrals, skipped none");
+        assert_eq!(summary(4, &skipped), "Scored 4 referrals, skipped 3 (2 no referral event, 1 unreadable timeline)");
+
+        let json = serde_json::to_value(&skipped[1]).unwrap();
+        assert_eq!(json["reason"], "parse_error");
+        assert_eq!(json["detail"], "not a list");
+        assert_eq!(serde_json::to_value(&skipped[0]).unwrap()["reason"], "no_referral_event");
+    }
+}
@@ -0,0 +1,182 @@
+// Whether areas and zones are improving, comparing the latest window of runs in
+// the history with the windows before it
+
+use std::collections::BTreeSet;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::history::{History, LatestResult};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrendSettings {
+    /// Days in each window, 7 for week over week
+    pub window_days: i64,
+    /// How many windows before the current one it's compared with, on average
+    pub previous_windows: usize,
+}
+
+impl Default for TrendSettings {
+    fn default() -> Self {
+        Self {
+            window_days: 7,
+            previous_windows: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Trends {
+    pub areas: Vec<Trend>,
+    pub zones: Vec<Trend>,
+}
+
+/// The current window next to the previous ones. Deltas are current minus previous,
+/// and times are decimal days like the rows.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Trend {
+    pub name: String,
+    pub referrals: usize,
+    pub previous_referrals: Option<f64>,
+    pub success_rate: Option<f64>,
+    pub success_rate_delta: Option<f64>,
+    pub contact_time: Option<f64>,
+    pub contact_time_delta: Option<f64>,
+}
+
+/// One window's results for one area or zone
+struct WindowStats {
+    referrals: usize,
+    success_rate: Option<f64>,
+    contact_time: Option<f64>,
+}
+
+impl WindowStats {
+    fn of<'a>(results: impl Iterator<Item = &'a LatestResult>) -> Self {
+        let results: Vec<&LatestResult> = results.collect();
+        let referrals = results.len();
+        Self {
+            referrals,
+            success_rate: (referrals > 0).then(|| results.iter().filter(|r| r.successful).count() as f64 / referrals as f64),
+            contact_time: mean(results.iter().filter_map(|r| r.contact_time).map(|minutes| minutes as f64 / 1440.0)),
+        }
+    }
+}
+
+/// Trends as of `now`. Windows end at `now`, `now - window_days`, and so on.
+pub fn trends(history: &History, now: DateTime<Utc>, settings: &TrendSettings) -> anyhow::Result<Trends> {
+    let window = Duration::days(settings.window_days.max(1));
+    let windows = (0..=settings.previous_windows)
+        .map(|i| {
+            let to = now - window * i as i32;
+            history.latest_results(to - window, to)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(Trends {
+        areas: compare(&windows, |r| Some(r.area.clone())),
+        zones: compare(&windows, |r| r.zone.clone()),
+    })
+}
+
+/// A trend for every name seen in any window, sorted by name
+fn compare(windows: &[Vec<LatestResult>], key: impl Fn(&LatestResult) -> Option<String>) -> Vec<Trend> {
+    let names: BTreeSet<String> = windows.iter().flatten().filter_map(&key).collect();
+    names
+        .into_iter()
+        .map(|name| {
+            let mut stats = windows
+                .iter()
+                .map(|results| WindowStats::of(results.iter().filter(|r| key(r).as_ref() == Some(&name))));
+            let current = stats.next().expect("there is always a current window");
+            let previous: Vec<WindowStats> = stats.collect();
+            let previous_rate = mean(previous.iter().filter_map(|s| s.success_rate));
+            let previous_time = mean(previous.iter().filter_map(|s| s.contact_time));
+            Trend {
+                name,
+                referrals: current.referrals,
+                previous_referrals: mean(previous.iter().map(|s| s.referrals as f64)),
+                success_rate: current.success_rate,
+                success_rate_delta: current.success_rate.zip(previous_rate).map(|(now, then)| now - then),
+                contact_time: current.contact_time,
+                contact_time_delta: current.contact_time.zip(previous_time).map(|(now, then)| now - then),
+            }
+        })
+        .collect()
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let values: Vec<f64> = values.collect();
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        history::RunRecord,
+        mock::person,
+        persons::{Person, ReferralPerson},
+        scoring::Outcome,
+    };
+
+    fn referral(guid: &str, area: &str, minutes: usize, outcome: Outcome) -> ReferralPerson {
+        ReferralPerson::new(guid.to_string(), guid.to_string(), Some(minutes), Vec::new(), area.to_string(), outcome)
+    }
+
+    #[test]
+    fn compares_this_week_with_last_week() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = History::open(dir.path()).unwrap();
+        let now = Utc::now();
+        let people = Person::parse_lossy(serde_json::json!({ "persons": [
+            person("a", "Alex", "Riverside", now),
+            person("b", "Sam", "Riverside", now),
+            person("c", "Kim", "Riverside", now),
+        ]}));
+        let mut record = |days_ago: i64, results: Vec<ReferralPerson>| {
+            history
+                .record_run(&RunRecord {
+                    started_at: now - Duration::days(days_ago),
+                    people: &people,
+                    timelines: &[],
+                    results: &results,
+                })
+                .unwrap();
+        };
+        // Last week: one referral, contacted after two days and not reached
+        record(9, vec![referral("a", "Riverside", 2 * 1440, Outcome::Unsuccessful)]);
+        // This week: `b` is scored twice and only the newer result counts
+        record(3, vec![referral("b", "Riverside", 1440, Outcome::Unsuccessful)]);
+        record(
+            1,
+            vec![
+                referral("b", "Riverside", 1440, Outcome::Successful),
+                referral("c", "Riverside", 0, Outcome::Successful),
+            ],
+        );
+
+        let trends = trends(&history, now, &TrendSettings::default()).unwrap();
+        assert_eq!(
+            trends.areas,
+            vec![Trend {
+                name: "Riverside".to_string(),
+                referrals: 2,
+                previous_referrals: Some(1.0),
+                success_rate: Some(1.0),
+                success_rate_delta: Some(1.0),
+                contact_time: Some(0.5),
+                contact_time_delta: Some(-1.5),
+            }]
+        );
+        assert_eq!(trends.zones[0].name, "Mock Zone");
+        assert_eq!(trends.zones[0].referrals, 2);
+
+        let no_history = TrendSettings {
+            previous_windows: 0,
+            ..Default::default()
+        };
+        let trends = super::trends(&history, now, &no_history).unwrap();
+        assert_eq!((trends.areas[0].previous_referrals, trends.areas[0].success_rate_delta), (None, None));
+    }
+}
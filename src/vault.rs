@@ -0,0 +1,220 @@
+// Encrypted credential store kept in the working path
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+pub const VAULT_FILE: &str = "credentials.vault";
+const SEALED_VERSION: u8 = 1;
+
+/// Argon2id cost parameters, stored next to the ciphertext so they can be raised later
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// Encrypted data. Binary fields are standard base64.
+/// `kdf` and `salt` are only present when the key came from a passphrase.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sealed {
+    pub v: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<KdfParams>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], params: KdfParams) -> anyhow::Result<[u8; 32]> {
+    let params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|e| anyhow::anyhow!("Invalid key derivation parameters: {e}"))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Key derivation failed: {e}"))?;
+    Ok(key)
+}
+
+/// Encrypts `plaintext` under a key derived from `passphrase` with a fresh salt and nonce
+pub fn seal_with_passphrase(plaintext: &[u8], passphrase: &str) -> anyhow::Result<Sealed> {
+    let kdf = KdfParams::default();
+    let mut salt = [0u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    let mut sealed = seal_with_key(plaintext, &derive_key(passphrase, &salt, kdf)?)?;
+    sealed.kdf = Some(kdf);
+    sealed.salt = Some(general_purpose::STANDARD.encode(salt));
+    Ok(sealed)
+}
+
+/// Encrypts `plaintext` under a raw 32 byte key with a fresh nonce
+pub fn seal_with_key(plaintext: &[u8], key: &[u8; 32]) -> anyhow::Result<Sealed> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new(key.into())
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+    Ok(Sealed {
+        v: SEALED_VERSION,
+        kdf: None,
+        salt: None,
+        nonce: general_purpose::STANDARD.encode(nonce),
+        ciphertext: general_purpose::STANDARD.encode(ciphertext),
+    })
+}
+
+/// Decrypts data sealed by [`seal_with_passphrase`]
+pub fn open_with_passphrase(sealed: &Sealed, passphrase: &str) -> anyhow::Result<Vec<u8>> {
+    let (Some(kdf), Some(salt)) = (sealed.kdf, &sealed.salt) else {
+        return Err(anyhow::anyhow!("This data was not sealed with a passphrase"));
+    };
+    let salt = general_purpose::STANDARD.decode(salt)?;
+    open_with_key(sealed, &derive_key(passphrase, &salt, kdf)?)
+        .map_err(|_| anyhow::anyhow!("Wrong passphrase, or the data has been tampered with"))
+}
+
+/// Decrypts data sealed by [`seal_with_key`]
+pub fn open_with_key(sealed: &Sealed, key: &[u8; 32]) -> anyhow::Result<Vec<u8>> {
+    if sealed.v != SEALED_VERSION {
+        return Err(anyhow::anyhow!("Unsupported sealed data version {}", sealed.v));
+    }
+    let nonce = general_purpose::STANDARD.decode(&sealed.nonce)?;
+    let ciphertext = general_purpose::STANDARD.decode(&sealed.ciphertext)?;
+    if nonce.len() != 24 {
+        return Err(anyhow::anyhow!("Sealed nonce has the wrong length"));
+    }
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| anyhow::anyhow!("Wrong key, or the data has been tampered with"))
+}
+
+/// Credentials encrypted at rest in `working_path/credentials.vault`
+#[derive(Debug)]
+pub struct Vault {
+    path: PathBuf,
+    passphrase: String,
+    entries: BTreeMap<String, String>,
+}
+
+impl Vault {
+    pub fn path_in(working_path: &Path) -> PathBuf {
+        working_path.join(VAULT_FILE)
+    }
+
+    pub fn exists(working_path: &Path) -> bool {
+        Self::path_in(working_path).is_file()
+    }
+
+    /// Opens the vault, or starts an empty one if it hasn't been saved yet
+    pub fn open(working_path: &Path, passphrase: &str) -> anyhow::Result<Self> {
+        let path = Self::path_in(working_path);
+        let entries = if path.is_file() {
+            let sealed: Sealed = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+            serde_json::from_slice(&open_with_passphrase(&sealed, passphrase)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self {
+            path,
+            passphrase: passphrase.to_string(),
+            entries,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.entries.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.entries.remove(key).is_some()
+    }
+
+    /// Re-encrypts everything under a new passphrase. Call [`Vault::save`] afterwards.
+    pub fn rotate(&mut self, new_passphrase: &str) {
+        self.passphrase = new_passphrase.to_string();
+    }
+
+    /// Writes the vault with a fresh salt and nonce
+    pub fn save(&self) -> anyhow::Result<()> {
+        let sealed = seal_with_passphrase(&serde_json::to_vec(&self.entries)?, &self.passphrase)?;
+        std::fs::write(&self.path, serde_json::to_string_pretty(&sealed)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_rejects_wrong_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = Vault::open(dir.path(), "correct horse").unwrap();
+        vault.set("CHURCH_PASSWORD", "hunter2");
+        vault.save().unwrap();
+
+        let on_disk = std::fs::read_to_string(Vault::path_in(dir.path())).unwrap();
+        assert!(!on_disk.contains("hunter2"));
+
+        let vault = Vault::open(dir.path(), "correct horse").unwrap();
+        assert_eq!(vault.get("CHURCH_PASSWORD"), Some("hunter2"));
+        assert!(Vault::open(dir.path(), "battery staple").is_err());
+    }
+
+    #[test]
+    fn rotate_and_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = Vault::open(dir.path(), "old").unwrap();
+        vault.set("CHURCH_USERNAME", "elder");
+        vault.set("CRYPT_KEY", "key");
+        vault.save().unwrap();
+
+        let mut vault = Vault::open(dir.path(), "old").unwrap();
+        assert!(vault.remove("CRYPT_KEY"));
+        assert!(!vault.remove("CRYPT_KEY"));
+        vault.rotate("new");
+        vault.save().unwrap();
+
+        assert!(Vault::open(dir.path(), "old").is_err());
+        let vault = Vault::open(dir.path(), "new").unwrap();
+        assert_eq!(vault.keys().collect::<Vec<_>>(), vec!["CHURCH_USERNAME"]);
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let mut sealed = seal_with_passphrase(b"secret", "pass").unwrap();
+        let mut ciphertext = general_purpose::STANDARD.decode(&sealed.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        sealed.ciphertext = general_purpose::STANDARD.encode(ciphertext);
+        assert!(open_with_passphrase(&sealed, "pass").is_err());
+    }
+}